@@ -0,0 +1,406 @@
+use prime_sieve::config::Config;
+use prime_sieve::engine::{self, SieveError};
+use prime_sieve::fs::{self, FileError};
+use prime_sieve::sieve::{CancelToken, ThreadPool, ThreadPoolError};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use progress_bar::ProgressBar;
+use std::io::{stderr, stdin, stdout, BufRead, BufWriter, ErrorKind, IsTerminal, Write};
+use std::result::Result;
+use std::str::FromStr;
+
+pub const USAGE: &str = "Usage: prime_sieve [settings] [command]
+
+Commands:
+  extend --until <n>    sieve rounds until the primes file reaches n
+  extend --rounds <k>   sieve k rounds
+  count [x]             number of primes in the file, or up to any x
+  nth <n>               the n-th prime, counting from 1, beyond the file too
+  factor <n>            the prime factors of n > 0, with their exponents
+  is-prime <n>          whether n < 2^128 is prime, exits with 1 if not
+  range <a> <b>         the primes from a to b, both included
+  verify                check the primes file and its index
+  export                print every prime in the file, one per line
+  help                  print this message
+Without a command a round is sieved every time enter is pressed.
+
+Settings: --file, --compress, --max-mem-usage, --cores, --pin-cores,
+--wheel-primes and --config <file>, see `Config`.
+
+Ctrl-C cancels the sieve, rounds that are saved already stay. An interrupted
+round goes on from its checkpoints in <file>.journal the next time.
+
+Exit codes: 0 success, 1 not prime, 2 bad usage, 3 beyond the primes file,
+4 primes file error, 5 sieve error, 130 cancelled.";
+
+pub const SUCCESS: i32 = 0;
+pub const NOT_PRIME: i32 = 1;
+pub const BAD_USAGE: i32 = 2;
+pub const NOT_COVERED: i32 = 3;
+pub const FILE_ERROR: i32 = 4;
+pub const SIEVE_ERROR: i32 = 5;
+pub const CANCELLED: i32 = 130;
+
+pub enum Command {
+    Interactive,
+    ExtendUntil(u64),
+    ExtendRounds(u64),
+    Count(Option<u64>),
+    Nth(u64),
+    Factor(u64),
+    IsPrime(u128),
+    Range(u64, u64),
+    Verify,
+    Export,
+    Help,
+}
+
+impl Command {
+    /// Parses the arguments that are left after the settings.
+    pub fn parse(args: &[String]) -> Result<Command, String> {
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        match args[..] {
+            [] => Ok(Command::Interactive),
+            ["extend", "--until", n] => Ok(Command::ExtendUntil(number(n)?)),
+            ["extend", "--rounds", k] => Ok(Command::ExtendRounds(number(k)?)),
+            ["count"] => Ok(Command::Count(None)),
+            ["count", x] => Ok(Command::Count(Some(number(x)?))),
+            ["nth", n] => {
+                match number(n)? {
+                    0 => Err("Primes are counted from 1.".to_string()),
+                    n => Ok(Command::Nth(n)),
+                }
+            }
+            ["factor", n] => {
+                match number(n)? {
+                    0 => Err("Zero has no prime factors.".to_string()),
+                    n => Ok(Command::Factor(n)),
+                }
+            }
+            ["is-prime", n] => Ok(Command::IsPrime(number(n)?)),
+            ["range", a, b] => {
+                match (number(a)?, number(b)?) {
+                    (a, b) if a > b => Err(format!("The range {} to {} is empty.", a, b)),
+                    (a, b) => Ok(Command::Range(a, b)),
+                }
+            }
+            ["verify"] => Ok(Command::Verify),
+            ["export"] => Ok(Command::Export),
+            ["help"] | ["--help"] | ["-h"] => Ok(Command::Help),
+            _ => Err(format!("Unknown command `{}`.", args.join(" "))),
+        }
+    }
+
+    /// Runs the command and returns the exit code of the process.
+    pub fn run(&self, config: &Config) -> Result<i32, CliError> {
+        match *self {
+            Command::Interactive => interactive(config),
+            Command::ExtendUntil(n) => extend(config, |highest, _| highest >= n),
+            Command::ExtendRounds(k) => extend(config, |_, rounds| rounds >= k),
+            Command::Count(None) => {
+                let pager = fs::load_primes_paged(config.file.clone(), config.page_size())?;
+                println!("{}", pager.header().count);
+                Ok(SUCCESS)
+            }
+            Command::Count(Some(x)) => {
+                println!("{}", engine::count_primes(config, x)?);
+                Ok(SUCCESS)
+            }
+            Command::Nth(n) => {
+                let thread_pool = thread_pool(config)?;
+                let prime = engine::nth_prime(&thread_pool, config, n)?;
+                thread_pool.stop().map_err(SieveError::Thread)?;
+                match prime {
+                    Some(prime) => {
+                        println!("{}", prime);
+                        Ok(SUCCESS)
+                    }
+                    None => Err(CliError::NotCovered("There is no 0th prime.".to_string())),
+                }
+            }
+            Command::Factor(n) => {
+                let factors: Vec<String> = engine::factor(config, n)?
+                    .iter()
+                    .map(|&(p, e)| if e == 1 { p.to_string() } else { format!("{}^{}", p, e) })
+                    .collect();
+                println!("{}", factors.join(" "));
+                Ok(SUCCESS)
+            }
+            Command::IsPrime(n) => {
+                let is_prime = if n <= u64::MAX as u128 {
+                    engine::is_prime(config, n as u64)?
+                } else {
+                    engine::is_prime_u128(config, n)?
+                };
+                println!("{}", is_prime);
+                Ok(if is_prime { SUCCESS } else { NOT_PRIME })
+            }
+            Command::Range(a, b) => {
+                let mut index = fs::open_index(config.file.clone())?;
+                let known = if b < index.header().sieve_limit {
+                    Some(index.primes_between(a, b + 1)?)
+                } else {
+                    None
+                };
+                let primes = covered(known, index.header())?;
+                print_primes(&primes)?;
+                Ok(SUCCESS)
+            }
+            Command::Verify => verify(config),
+            Command::Export => {
+                let mut reader = fs::read_primes_paged(config.file.clone(), config.page_size())?;
+                for chunk in reader.chunks() {
+                    print_primes(&chunk?)?;
+                }
+                Ok(SUCCESS)
+            }
+            Command::Help => {
+                println!("{}", USAGE);
+                Ok(SUCCESS)
+            }
+        }
+    }
+}
+
+fn number<T: FromStr>(arg: &str) -> Result<T, String> {
+    arg.replace('_', "").parse().map_err(|_| format!("`{}` is not a number.", arg))
+}
+
+fn covered<T>(answer: Option<T>, header: &fs::Header) -> Result<T, CliError> {
+    match answer {
+        Some(answer) => Ok(answer),
+        None => {
+            Err(CliError::NotCovered(format!("The primes file only reaches {}.",
+                                             header.highest_prime)))
+        }
+    }
+}
+
+fn print_primes(primes: &[u64]) -> Result<(), CliError> {
+    let out = stdout();
+    let mut out = BufWriter::new(out.lock());
+    for prime in primes {
+        writeln!(out, "{}", prime).map_err(FileError::IO)?;
+    }
+    out.flush().map_err(FileError::IO)?;
+    Ok(())
+}
+
+/// A thread pool that shows its progress when stderr is a terminal, and that Ctrl-C
+/// cancels.
+fn thread_pool(config: &Config) -> Result<ThreadPool, CliError> {
+    let mut thread_pool = ThreadPool::from_config(config).map_err(SieveError::Thread)?;
+    if stderr().is_terminal() {
+        thread_pool.set_progress_sink(Box::new(ProgressBar::new()));
+    }
+    cancel_on_interrupt(thread_pool.cancel_token());
+    Ok(thread_pool)
+}
+
+/// The first Ctrl-C cancels through the token, the second ends the process as usual.
+/// Reads are not restarted after it, so one waiting for a line gets to see it.
+#[cfg(unix)]
+fn cancel_on_interrupt(token: CancelToken) {
+    static INTERRUPT: ::std::sync::OnceLock<CancelToken> = ::std::sync::OnceLock::new();
+
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        if let Some(token) = INTERRUPT.get() {
+            token.cancel();
+        }
+    }
+
+    if INTERRUPT.set(token).is_ok() {
+        let handler: extern "C" fn(libc::c_int) = on_interrupt;
+        unsafe {
+            let mut action: libc::sigaction = ::std::mem::zeroed();
+            action.sa_sigaction = handler as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGINT, &action, ::std::ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn cancel_on_interrupt(_: CancelToken) {}
+
+/// Highest prime in the primes file, 0 if there is no file yet.
+fn highest_prime(config: &Config) -> Result<u64, CliError> {
+    match fs::load_primes_paged(config.file.clone(), config.page_size()) {
+        Ok(pager) => Ok(pager.header().highest_prime),
+        Err(FileError::IO(ref err)) if err.kind() == ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(CliError::from(err)),
+    }
+}
+
+/// Sieves rounds until `done(highest prime, rounds)` holds.
+fn extend<F>(config: &Config, done: F) -> Result<i32, CliError>
+    where F: Fn(u64, u64) -> bool
+{
+    let thread_pool = thread_pool(config)?;
+    let mut highest = highest_prime(config)?;
+    let mut rounds = 0;
+
+    while !done(highest, rounds) {
+        let primes = engine::sieve_round(&thread_pool, config)?;
+        update_index(config);
+        match primes.last() {
+            Some(&last) if last > highest => highest = last,
+            _ => return Err(CliError::NoProgress(highest)),
+        }
+        rounds += 1;
+        eprintln!("Round {}: primes up to {}", rounds, highest);
+    }
+
+    thread_pool.stop().map_err(SieveError::Thread)?;
+    println!("{}", highest);
+    Ok(SUCCESS)
+}
+
+/// Brings the index up to date after a save. The primes are saved either way, so a
+/// failure is only a warning: the index catches up the next time it is opened.
+fn update_index(config: &Config) {
+    if let Err(err) = fs::update_index(&config.file) {
+        eprintln!("Warning: the index of the primes file was not updated.\n{}", err);
+    }
+}
+
+/// Reads the whole primes file and checks that it is consistent with its header and
+/// its index. The index is checked as it is, so a missing or stale one fails.
+fn verify(config: &Config) -> Result<i32, CliError> {
+    let pager = fs::load_primes_paged(config.file.clone(), config.page_size())?;
+    let (count, highest) = (pager.header().count, pager.header().highest_prime);
+
+    let mut read = 0;
+    let mut last = 0;
+    for page in pager {
+        for prime in page? {
+            if prime <= last {
+                return Err(CliError::File(FileError::Corrupted(format!("{} follows {}.",
+                                                                       prime,
+                                                                       last))));
+            }
+            last = prime;
+            read += 1;
+        }
+    }
+    if read != count || last != highest {
+        return Err(CliError::File(FileError::Corrupted("The primes do not match the header."
+            .to_string())));
+    }
+
+    let mut index = fs::open_index_read_only(config.file.clone())?;
+    if index.nth(count)? != if count == 0 { None } else { Some(highest) } {
+        return Err(CliError::File(FileError::Corrupted("The index does not match the primes."
+            .to_string())));
+    }
+
+    println!("{} primes up to {}", count, highest);
+    Ok(SUCCESS)
+}
+
+/// The old interactive mode: one round for every line on stdin, until it ends.
+fn interactive(config: &Config) -> Result<i32, CliError> {
+    let thread_pool = thread_pool(config)?;
+    loop {
+        if let Ok(primes_pager) = fs::load_primes_paged(config.file.clone(), config.page_size()) {
+            println!("Loaded {} primes up to {}",
+                     primes_pager.header().count,
+                     primes_pager.header().highest_prime);
+        }
+
+        let primes = engine::sieve_round(&thread_pool, config)?;
+        update_index(config);
+        println!("Sieve found primes {:?}", primes);
+        println!("saved");
+        let line = read_line(&thread_pool.cancel_token());
+        if thread_pool.cancel_token().is_cancelled() {
+            thread_pool.stop().map_err(SieveError::Thread)?;
+            return Ok(CANCELLED);
+        }
+        if line.is_none() {
+            break;
+        }
+    }
+
+    thread_pool.stop().map_err(SieveError::Thread)?;
+    Ok(SUCCESS)
+}
+
+/// The next line on stdin, None once it ends or the token is cancelled. Unlike
+/// `Stdin::read_line` it gives up on a read that a signal interrupts.
+fn read_line(token: &CancelToken) -> Option<String> {
+    let mut input = stdin().lock();
+    let mut line = Vec::new();
+    loop {
+        let (used, done) = match input.fill_buf() {
+            Ok([]) if line.is_empty() => return None,
+            Ok([]) => break,
+            Ok(buf) => {
+                match buf.iter().position(|&byte| byte == b'\n') {
+                    Some(end) => {
+                        line.extend_from_slice(&buf[..end + 1]);
+                        (end + 1, true)
+                    }
+                    None => {
+                        line.extend_from_slice(buf);
+                        (buf.len(), false)
+                    }
+                }
+            }
+            Err(ref err) if err.kind() == ErrorKind::Interrupted && !token.is_cancelled() => {
+                continue
+            }
+            Err(_) => return None,
+        };
+        input.consume(used);
+        if done {
+            break;
+        }
+    }
+    Some(String::from_utf8_lossy(&line).into_owned())
+}
+
+pub enum CliError {
+    File(FileError),
+    Sieve(SieveError),
+    NotCovered(String),
+    NoProgress(u64),
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            CliError::File(_) |
+            CliError::Sieve(SieveError::File(_)) => FILE_ERROR,
+            CliError::NotCovered(_) => NOT_COVERED,
+            CliError::Sieve(SieveError::Thread(ThreadPoolError::Cancelled)) => CANCELLED,
+            CliError::Sieve(_) |
+            CliError::NoProgress(_) => SIEVE_ERROR,
+        }
+    }
+}
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            CliError::File(ref err) => write!(f, "{}", err),
+            CliError::Sieve(ref err) => write!(f, "Error in sieve:\n{}", err),
+            CliError::NotCovered(ref msg) => write!(f, "{}", msg),
+            CliError::NoProgress(highest) => {
+                write!(f, "The sieve found no primes above {}.", highest)
+            }
+        }
+    }
+}
+
+impl From<FileError> for CliError {
+    fn from(err: FileError) -> CliError {
+        CliError::File(err)
+    }
+}
+
+impl From<SieveError> for CliError {
+    fn from(err: SieveError) -> CliError {
+        CliError::Sieve(err)
+    }
+}
@@ -1,10 +1,382 @@
-// Edit this file for compiletime configurations
+// Defaults of the runtime configuration, see `Config` below. Edit them to change the
+// defaults for every run.
+
+use std::env;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::File;
+use std::io::{Error as IOError, Read};
+use std::result::Result;
+use std::str::FromStr;
+use std::thread;
+use fs::Encoding;
+use sieve::math::{MAX_WHEEL_PRIMES, MIN_WHEEL_PRIMES};
 
 // The file where we should store the primes
-pub const FILE: &'static str = "primes.bin";
+pub const FILE: &str = "primes.bin";
+
+// Store the gaps between primes as varints in new primes files instead of full u64s.
+// Takes roughly an eighth of the space.
+pub const COMPRESS: bool = false;
 
 // Maximum number RAM that the primes are allowed to take up. In bytes.
 pub const MAX_MEM_USAGE: usize = 1073741824 / 8;
 
-// Number of cores on the computer
-pub const CORES: usize = 4;
+// Number of worker threads. None uses the parallelism available to the process,
+// which takes CPU affinity and cgroup quotas into account.
+pub const CORES: Option<usize> = None;
+
+// Number of worker threads if the available parallelism cannot be found out.
+pub const FALLBACK_CORES: usize = 4;
+
+// Pin every worker thread to its own CPU, for stable benchmarks. Linux only.
+pub const PIN_CORES: bool = false;
+
+// Number of primes the sieve wheel is built from: 3 (mod 30), 4 (mod 210) or 5 (mod 2310).
+// None picks the biggest wheel that fits the memory budget of a thread.
+pub const WHEEL_PRIMES: Option<usize> = None;
+
+// Number of wheel spokes the sieve crosses off at once. Should fit in the L1/L2 cache.
+pub const SEGMENT_SIZE: usize = 32768;
+
+// Prefix of the environment variables that override the defaults, e.g. PRIME_SIEVE_CORES.
+pub const ENV_PREFIX: &str = "PRIME_SIEVE_";
+
+// Environment variable naming a config file to read, see `Config::load`.
+pub const CONFIG_FILE_ENV: &str = "PRIME_SIEVE_CONFIG";
+
+const KEYS: &[&str] = &["file", "compress", "max_mem_usage", "cores", "pin_cores", "wheel_primes"];
+
+/// Settings of a run. Every setting has a key that is used in all three places it
+/// can come from:
+///
+/// * config file: `key = value` lines, `#` starts a comment
+/// * environment: `PRIME_SIEVE_KEY=value`
+/// * command line: `--key value` or `--key=value`, dashes or underscores alike
+///
+/// Keys are `file`, `compress`, `max_mem_usage`, `cores` (`auto` for the available
+/// parallelism), `pin_cores` and `wheel_primes` (`auto` for the biggest wheel that
+/// fits).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub file: String,
+    pub compress: bool,
+    pub max_mem_usage: usize,
+    pub cores: usize,
+    pub pin_cores: bool,
+    pub wheel_primes: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            file: FILE.to_string(),
+            compress: COMPRESS,
+            max_mem_usage: MAX_MEM_USAGE,
+            cores: CORES.unwrap_or_else(available_cores),
+            pin_cores: PIN_CORES,
+            wheel_primes: WHEEL_PRIMES,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration of the process: defaults, then the config file
+    /// (`--config PATH` or `PRIME_SIEVE_CONFIG`), then the environment, then the
+    /// command line, each overriding the one before. Returns the configuration
+    /// and the arguments that are not settings.
+    pub fn load<I>(args: I) -> Result<(Config, Vec<String>), ConfigError>
+        where I: IntoIterator<Item = String>
+    {
+        let args: Vec<String> = args.into_iter().collect();
+        let mut config = Config::default();
+
+        let config_file = match flag_value(&args, "config") {
+            Some(path) => Some(path),
+            None => env::var(CONFIG_FILE_ENV).ok(),
+        };
+        if let Some(path) = config_file {
+            config.read_file(&path)?;
+        }
+
+        config.read_env(env::vars())?;
+        let rest = config.parse_args(args)?;
+        Ok((config, rest))
+    }
+
+    /// Sets the setting with the key. Dashes in the key count as underscores. The
+    /// config is left as it was if the value is invalid.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let value = value.trim();
+        let mut next = self.clone();
+        match key.trim().replace('-', "_").as_str() {
+            "file" => next.file = value.to_string(),
+            "compress" => next.compress = parse(key, value)?,
+            "max_mem_usage" => next.max_mem_usage = parse(key, value)?,
+            "cores" if value == "auto" => next.cores = available_cores(),
+            "cores" => next.cores = parse(key, value)?,
+            "pin_cores" => next.pin_cores = parse(key, value)?,
+            "wheel_primes" if value == "auto" => next.wheel_primes = None,
+            "wheel_primes" => next.wheel_primes = Some(parse(key, value)?),
+            _ => return Err(ConfigError::Invalid(format!("Unknown setting `{}`.", key))),
+        }
+        next.check()?;
+        *self = next;
+        Ok(())
+    }
+
+    pub fn read_file(&mut self, path: &str) -> Result<(), ConfigError> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        self.read_str(&content)
+    }
+
+    /// Reads the `key = value` lines of a config file.
+    pub fn read_str(&mut self, content: &str) -> Result<(), ConfigError> {
+        for (no, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            match line.find('=') {
+                Some(at) => self.set(&line[..at], &line[at + 1..])?,
+                None => {
+                    return Err(ConfigError::Invalid(format!("Line {} of the config file is not \
+                                                             `key = value`.",
+                                                            no + 1)))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the settings from `PRIME_SIEVE_*` variables. Other variables are ignored.
+    pub fn read_env<I>(&mut self, vars: I) -> Result<(), ConfigError>
+        where I: IntoIterator<Item = (String, String)>
+    {
+        for (name, value) in vars {
+            if name == CONFIG_FILE_ENV || !name.starts_with(ENV_PREFIX) {
+                continue;
+            }
+            self.set(&name[ENV_PREFIX.len()..].to_lowercase(), &value)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the `--key value` and `--key=value` settings, and `--compress` and
+    /// `--pin-cores` on their own. Returns the other arguments in order.
+    pub fn parse_args<I>(&mut self, args: I) -> Result<Vec<String>, ConfigError>
+        where I: IntoIterator<Item = String>
+    {
+        let mut rest = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (key, value) = match arg.strip_prefix("--") {
+                Some(flag) => {
+                    match flag.find('=') {
+                        Some(at) => (flag[..at].replace('-', "_"), Some(flag[at + 1..].to_string())),
+                        None => (flag.replace('-', "_"), None),
+                    }
+                }
+                None => {
+                    rest.push(arg);
+                    continue;
+                }
+            };
+            if key != "config" && !KEYS.contains(&key.as_str()) {
+                rest.push(arg);
+                continue;
+            }
+
+            let value = match value {
+                Some(value) => value,
+                None if key == "compress" || key == "pin_cores" => "true".to_string(),
+                None => {
+                    match args.next() {
+                        Some(value) => value,
+                        None => {
+                            return Err(ConfigError::Invalid(format!("{} needs a value.", arg)))
+                        }
+                    }
+                }
+            };
+            // The config file was read before everything else, see `load`.
+            if key != "config" {
+                self.set(&key, &value)?;
+            }
+        }
+        Ok(rest)
+    }
+
+    /// Number of primes per page of the primes file. Half the memory goes to the page.
+    pub fn page_size(&self) -> usize {
+        (self.max_mem_usage / 2 / 8).max(1)
+    }
+
+    /// Memory budget of one worker thread, in bytes. Without cores there is one
+    /// thread.
+    pub fn mem_per_thread(&self) -> usize {
+        self.max_mem_usage / self.cores.max(1)
+    }
+
+    /// Encoding of newly created primes files.
+    pub fn encoding(&self) -> Encoding {
+        if self.compress {
+            Encoding::DeltaVarint
+        } else {
+            Encoding::Raw
+        }
+    }
+
+    /// Checks the settings against each other. Every config that `set` leaves
+    /// behind passes.
+    pub fn check(&self) -> Result<(), ConfigError> {
+        if self.cores == 0 {
+            return Err(ConfigError::Invalid("At least one core is needed.".to_string()));
+        }
+        if self.max_mem_usage < self.cores {
+            return Err(ConfigError::Invalid("The memory budget is too small for the cores."
+                .to_string()));
+        }
+        if let Some(no_primes) = self.wheel_primes {
+            if !(MIN_WHEEL_PRIMES..=MAX_WHEEL_PRIMES).contains(&no_primes) {
+                return Err(ConfigError::Invalid(format!("Wheels are built from {} to {} \
+                                                         primes, not {}.",
+                                                        MIN_WHEEL_PRIMES,
+                                                        MAX_WHEEL_PRIMES,
+                                                        no_primes)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Number of threads the process can run at once, see
+/// `std::thread::available_parallelism`.
+pub fn available_cores() -> usize {
+    thread::available_parallelism().map_or(FALLBACK_CORES, |cores| cores.get())
+}
+
+fn flag_value(args: &[String], key: &str) -> Option<String> {
+    let flag = format!("--{}", key);
+    let prefix = format!("--{}=", key);
+    for (i, arg) in args.iter().enumerate() {
+        if *arg == flag {
+            return args.get(i + 1).cloned();
+        }
+        if arg.starts_with(&prefix) {
+            return Some(arg[prefix.len()..].to_string());
+        }
+    }
+    None
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse()
+        .map_err(|_| ConfigError::Invalid(format!("`{}` is not a valid value for `{}`.", value, key)))
+}
+
+pub enum ConfigError {
+    IO(IOError),
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            ConfigError::IO(ref err) => write!(f, "Cannot read the config file: \n\t{}", err),
+            ConfigError::Invalid(ref msg) => write!(f, "Invalid configuration: {}", msg),
+        }
+    }
+}
+
+impl From<IOError> for ConfigError {
+    fn from(err: IOError) -> ConfigError {
+        ConfigError::IO(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{available_cores, Config, ConfigError};
+    use std::fs::{remove_file, File};
+    use std::io::Write;
+    use test_util::temp_file;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let mut config = Config::default();
+        assert!(config.read_str("# primes\nfile = a.bin\ncores = 2 # two\nmax_mem_usage=4096\n")
+            .is_ok());
+        let vars = vec![("PRIME_SIEVE_FILE".to_string(), "b.bin".to_string()),
+                        ("PATH".to_string(), "/bin".to_string())];
+        assert!(config.read_env(vars).is_ok());
+        let rest = config.parse_args(args(&["--max-mem-usage", "8192", "--compress", "count"]));
+
+        assert_eq!(rest.ok(), Some(args(&["count"])));
+        assert_eq!(config,
+                   Config {
+                       file: "b.bin".to_string(),
+                       compress: true,
+                       max_mem_usage: 8192,
+                       cores: 2,
+                       ..Config::default()
+                   });
+        assert_eq!(config.page_size(), 512);
+        assert_eq!(config.mem_per_thread(), 4096);
+    }
+
+    #[test]
+    fn passes_on_arguments_that_are_not_settings() {
+        let mut config = Config::default();
+        let rest = config.parse_args(args(&["nth", "--until", "100", "--cores=3", "7"]));
+        assert_eq!(rest.ok(), Some(args(&["nth", "--until", "100", "7"])));
+        assert_eq!(config.cores, 3);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let mut config = Config::default();
+        for line in &["cores = 0", "cores = many", "wheel_primes = 7", "colour = red", "cores"] {
+            match config.read_str(line) {
+                Err(ConfigError::Invalid(_)) => {}
+                _ => panic!("`{}` was accepted", line),
+            }
+        }
+        assert!(config.parse_args(args(&["--file"])).is_err());
+        assert!(config.set("wheel_primes", "auto").is_ok());
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn detects_cores_unless_given() {
+        let mut config = Config::default();
+        assert!(available_cores() >= 1);
+        assert_eq!(config.cores, available_cores());
+
+        let rest = config.parse_args(args(&["--cores", "3", "--pin-cores"]));
+        assert_eq!(rest.ok(), Some(Vec::new()));
+        assert_eq!((config.cores, config.pin_cores), (3, true));
+        assert!(config.set("cores", "auto").is_ok());
+        assert_eq!(config.cores, available_cores());
+    }
+
+    #[test]
+    fn reads_config_file() {
+        let path = temp_file("config");
+        File::create(&path).unwrap().write_all(b"wheel_primes = 4\n").unwrap();
+
+        let mut config = Config::default();
+        assert!(config.read_file(&path).is_ok());
+        assert_eq!(config.wheel_primes, Some(4));
+        let _ = remove_file(&path);
+
+        match config.read_file(&path) {
+            Err(ConfigError::IO(_)) => {}
+            _ => panic!("missing config file was read"),
+        }
+    }
+}
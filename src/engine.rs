@@ -0,0 +1,401 @@
+use config::Config;
+use sieve::{math, ThreadPool, ThreadPoolError};
+use sieve::math::{CandidateSet, Partition};
+use fs::{self, FileError};
+use std::result::Result;
+use std::io::ErrorKind;
+use std::vec::Vec;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::convert::From;
+use std::mem::size_of;
+
+/// Primes below this are tried as factors by `is_prime_u128`. Past it a division
+/// costs more than the share of composites it still catches.
+pub const SMALL_PRIME_LIMIT: u64 = 1 << 16;
+
+/// π(2^64), so the n-th prime is beyond u64 for any bigger n.
+pub const PRIMES_IN_U64: u64 = 425_656_284_035_217_743;
+
+/// Primes of the primes file below this are tried as factors before Pollard's rho.
+/// Rho finds factors of about this size in a thousand steps or so, while dividing by
+/// all primes below it already takes tens of thousands.
+pub const FACTOR_TRIAL_LIMIT: u64 = 1 << 20;
+
+pub enum SieveError {
+    File(FileError),
+    Thread(ThreadPoolError),
+    PrimesFileEmpty,
+    /// The request is beyond u64, or needs more memory than `max_mem_usage` allows.
+    Limit(String),
+}
+
+/// Sieves the next round of primes above the highest prime of the primes file in the
+/// config, which the round does not include again. The first page of the file finds
+/// the candidates, and the pages after it sieve them until their primes are too big
+/// to matter. The progress sink sees the whole round as one request, in stages.
+/// Nothing is journaled, see `sieve_round` for that.
+pub fn sieve_file(thread_pool: &ThreadPool, config: &Config) -> Result<Vec<u64>, SieveError> {
+    thread_pool.as_one_request("sieving a round", || sieve_pages(thread_pool, config, false))
+}
+
+/// The round of `sieve_file`, checkpointed to the journal next to the primes file
+/// if `journaled`.
+fn sieve_pages(thread_pool: &ThreadPool,
+               config: &Config,
+               journaled: bool)
+               -> Result<Vec<u64>, SieveError> {
+    let mut primes_pager = fs::load_primes_paged(config.file.clone(), config.page_size())?;
+    let round = fs::Round {
+        highest_prime: primes_pager.header().highest_prime,
+        count: primes_pager.header().count,
+        page_size: config.page_size() as u64,
+    };
+    let init_primes = match primes_pager.next() {
+        Some(page) => page?,
+        None => return Err(SieveError::PrimesFileEmpty),
+    };
+
+    let units = thread_pool.round_units(*init_primes.last().unwrap(), round.highest_prime + 1)?;
+    let units: Vec<(u64, u64)> =
+        units.iter().map(|unit| (unit.from, unit.from + unit.delta)).collect();
+    let mut journal = if journaled {
+        Some(fs::open_journal(&config.file, &round, units.clone())?)
+    } else {
+        None
+    };
+    let units = journal.as_ref().map_or(units, |journal| journal.units().to_vec());
+    let checkpoints = journal.as_mut().map_or(Vec::new(), fs::Journal::take_checkpoints);
+
+    // Every unit with the last stage it is done with, from its checkpoint if it has one.
+    let mut sets: Vec<Option<(u32, CandidateSet)>> = units.iter().map(|_| None).collect();
+    for checkpoint in checkpoints {
+        let wheel = thread_pool.wheel().clone();
+        let mut set = CandidateSet::new(wheel, checkpoint.from, checkpoint.to);
+        for n in checkpoint.numbers {
+            set.insert(n);
+        }
+        sets[checkpoint.unit] = Some((checkpoint.stage, set));
+    }
+
+    let missing: Vec<usize> = (0..units.len()).filter(|&i| sets[i].is_none()).collect();
+    if !missing.is_empty() {
+        let partitions = missing.iter()
+            .map(|&i| Partition { from: units[i].0, delta: units[i].1 - units[i].0 })
+            .collect();
+        let found = checkpointed(journal.as_mut(), &missing, 0, |completed| {
+            thread_pool.find_candidates_in(init_primes, partitions, completed)
+        })?;
+        for (i, set) in missing.into_iter().zip(found) {
+            sets[i] = Some((0, set));
+        }
+    }
+
+    let end = units.last().map_or(0, |&(_, to)| to) as u128;
+    for (stage, page) in (1..).zip(primes_pager) {
+        let page = page?;
+        if page.first().is_some_and(|&p| p as u128 * p as u128 >= end) {
+            break;
+        }
+        let behind: Vec<usize> = (0..units.len())
+            .filter(|&i| sets[i].as_ref().is_some_and(|&(done, _)| done < stage))
+            .collect();
+        if behind.is_empty() {
+            continue;
+        }
+
+        let candidates = behind.iter().flat_map(|&i| sets[i].take()).map(|(_, set)| set).collect();
+        let sieved = checkpointed(journal.as_mut(), &behind, stage, |completed| {
+            thread_pool.sieve_with(page, candidates, completed)
+        })?;
+        for (i, set) in behind.into_iter().zip(sieved) {
+            sets[i] = Some((stage, set));
+        }
+    }
+
+    Ok(sets.iter().flatten().flat_map(|(_, set)| set.iter()).collect())
+}
+
+/// Runs a request of the thread pool on the given units of the round, and journals
+/// every set it completes as done with the stage if there is a journal.
+fn checkpointed<R>(mut journal: Option<&mut fs::Journal>,
+                   units: &[usize],
+                   stage: u32,
+                   request: R)
+                   -> Result<Vec<CandidateSet>, SieveError>
+    where R: FnOnce(&mut dyn FnMut(usize, &CandidateSet))
+                    -> Result<Vec<CandidateSet>, ThreadPoolError>
+{
+    let mut failed = None;
+    let sets = request(&mut |i, set| match journal {
+        Some(ref mut journal) if failed.is_none() => {
+            if let Err(err) = journal.append(units[i], stage, set.iter().collect()) {
+                failed = Some(err);
+            }
+        }
+        _ => {}
+    })?;
+    match failed {
+        Some(err) => Err(SieveError::File(err)),
+        None => Ok(sets),
+    }
+}
+
+/// Like `sieve_file`, but a missing file is a fresh start from the initial primes.
+pub fn sieve(thread_pool: &ThreadPool, config: &Config) -> Result<Vec<u64>, SieveError> {
+    or_init_primes(sieve_file(thread_pool, config))
+}
+
+/// The primes of the round, or the initial primes if there was no primes file.
+fn or_init_primes(round: Result<Vec<u64>, SieveError>) -> Result<Vec<u64>, SieveError> {
+    match round {
+        Ok(primes) => Ok(primes),
+        Err(err) => {
+            match err {
+                SieveError::File(FileError::IO(ioerr)) => {
+                    match ioerr.kind() {
+                        ErrorKind::NotFound => Ok(math::init_primes()),
+                        _ => Err(SieveError::File(FileError::IO(ioerr))),
+                    }
+                }
+                _ => Err(err),
+            }
+        }
+    }
+}
+
+/// Sieves the next round like `sieve` and saves it. Every work unit that is done with
+/// a page is checkpointed to the journal next to the primes file, so a round that
+/// was interrupted goes on from where its units were; the journal is removed once
+/// the round is saved. Returns the new primes.
+pub fn sieve_round(thread_pool: &ThreadPool, config: &Config) -> Result<Vec<u64>, SieveError> {
+    let round = thread_pool.as_one_request("sieving a round", || {
+        sieve_pages(thread_pool, config, true)
+    });
+    let primes = or_init_primes(round)?;
+    save(&primes, config)?;
+    fs::remove_journal(&config.file)?;
+    Ok(primes)
+}
+
+/// Number of primes up to and including `x`. Counted in the primes file if it
+/// reaches `x`, and computed with `math::prime_count` otherwise, with the primes
+/// file as the table of small primes. Fails if the tables of the computation do not
+/// fit in `max_mem_usage`.
+pub fn count_primes(config: &Config, x: u64) -> Result<u64, SieveError> {
+    let mut index = match fs::open_index(config.file.clone()) {
+        Ok(index) => Some(index),
+        Err(FileError::IO(ref err)) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => return Err(SieveError::File(err)),
+    };
+    if let Some(count) = match index {
+        Some(ref mut index) => index.count_up_to(x)?,
+        None => None,
+    } {
+        return Ok(count);
+    }
+
+    let needed = math::prime_count_memory(x);
+    if needed > config.max_mem_usage as u64 {
+        return Err(SieveError::Limit(format!("Counting the primes up to {} needs {} bytes, \
+                                               more than max_mem_usage allows.",
+                                              x,
+                                              needed)));
+    }
+    let mut index = match index {
+        Some(index) => index,
+        None => return Ok(math::prime_count(x, &[])),
+    };
+    let root = (x as f64).sqrt() as u64 + 1;
+    let small_primes = index.primes_between(0, root.min(index.header().sieve_limit))?;
+    Ok(math::prime_count(x, &small_primes))
+}
+
+/// Whether n is prime. Looked up in the primes file if it reaches n, and decided by
+/// `math::is_prime` otherwise.
+pub fn is_prime(config: &Config, n: u64) -> Result<bool, FileError> {
+    let mut index = match fs::open_index(config.file.clone()) {
+        Ok(index) => index,
+        Err(FileError::IO(ref err)) if err.kind() == ErrorKind::NotFound => {
+            return Ok(math::is_prime(n))
+        }
+        Err(err) => return Err(err),
+    };
+
+    if n < index.header().sieve_limit {
+        Ok(!index.primes_between(n, n + 1)?.is_empty())
+    } else {
+        Ok(math::is_prime(n))
+    }
+}
+
+/// Whether n is prime, for n beyond u64. The primes below `SMALL_PRIME_LIMIT` from
+/// the primes file, or the built-in primes without one, weed out numbers with small
+/// factors before `math::is_prime_u128` runs the Baillie–PSW test. The file is only
+/// read up to that limit.
+pub fn is_prime_u128(config: &Config, n: u128) -> Result<bool, FileError> {
+    let mut small_primes = Vec::new();
+    match fs::read_primes_paged(config.file.clone(), config.page_size()) {
+        Ok(mut reader) => {
+            for chunk in reader.chunks() {
+                let chunk = chunk?;
+                small_primes.extend(chunk.iter().take_while(|&&p| p < SMALL_PRIME_LIMIT));
+                if chunk.last().is_some_and(|&p| p >= SMALL_PRIME_LIMIT) {
+                    break;
+                }
+            }
+        }
+        Err(FileError::IO(ref err)) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    if small_primes.is_empty() {
+        small_primes = math::init_primes();
+    }
+    Ok(math::is_prime_u128(n, &small_primes))
+}
+
+/// The prime factors of n with their exponents, ascending; empty for 0 and 1. The
+/// primes file is streamed for trial division up to `FACTOR_TRIAL_LIMIT`, and
+/// `math::factor_rest` takes over for what is left.
+pub fn factor(config: &Config, n: u64) -> Result<Vec<(u64, u32)>, FileError> {
+    let mut factors = Vec::new();
+    let mut rest = n;
+
+    match fs::read_primes_paged(config.file.clone(), config.page_size()) {
+        Ok(mut reader) => {
+            for chunk in reader.chunks() {
+                let chunk = chunk?;
+                let end = chunk.iter().position(|&p| p >= FACTOR_TRIAL_LIMIT);
+                math::trial_divide(&mut rest, &chunk[..end.unwrap_or(chunk.len())], &mut factors);
+                if rest == 1 || end.is_some() {
+                    break;
+                }
+            }
+        }
+        Err(FileError::IO(ref err)) if err.kind() == ErrorKind::NotFound => {
+            math::trial_divide(&mut rest, &math::init_primes(), &mut factors)
+        }
+        Err(err) => return Err(err),
+    }
+
+    math::factor_rest(rest, &mut factors);
+    Ok(factors)
+}
+
+/// The n-th prime, counting from 1; None for n = 0. Read from the primes file if it
+/// holds that many primes. Otherwise π is counted exactly a little below the
+/// estimate of `math::nth_prime_estimate`, and the thread pool sieves upwards from
+/// there until it reaches the n-th prime. Fails if that prime is beyond u64, or if
+/// the tables of the count do not fit in `max_mem_usage`.
+pub fn nth_prime(thread_pool: &ThreadPool, config: &Config, n: u64) -> Result<Option<u64>, SieveError> {
+    if n == 0 {
+        return Ok(None);
+    }
+    if n > PRIMES_IN_U64 {
+        return Err(SieveError::Limit(format!("Prime number {} is beyond u64, which holds \
+                                              {} primes.",
+                                             n,
+                                             PRIMES_IN_U64)));
+    }
+    let mut index = match fs::open_index(config.file.clone()) {
+        Ok(index) => Some(index),
+        Err(FileError::IO(ref err)) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => return Err(SieveError::File(err)),
+    };
+    if let Some(prime) = match index {
+        Some(ref mut index) => index.nth(n)?,
+        None => None,
+    } {
+        return Ok(Some(prime));
+    }
+
+    let estimate = math::nth_prime_estimate(n);
+    let mut margin = ((estimate as f64).sqrt() * (estimate as f64).ln()) as u64 + 100;
+    let limit = (2.0 * estimate as f64).sqrt() as u64 + 1;
+    // π(x) < 1.26 x / ln x bounds the length of the table.
+    let table_len = (1.26 * limit as f64 / (limit as f64).ln()) as u64 + 1;
+    let needed = table_len * size_of::<u64>() as u64 + math::prime_count_memory(estimate);
+    if needed > config.max_mem_usage as u64 {
+        return Err(SieveError::Limit(format!("Finding prime number {} needs {} bytes, more \
+                                              than max_mem_usage allows.",
+                                             n,
+                                             needed)));
+    }
+    let table = primes_up_to(thread_pool, index.as_mut(), limit)?;
+
+    // Step back until there are fewer than n primes below `from`.
+    let mut from = estimate.saturating_sub(margin).max(2);
+    let mut below = math::prime_count(from - 1, &table);
+    while below >= n {
+        margin *= 2;
+        from = from.saturating_sub(margin).max(2);
+        below = math::prime_count(from - 1, &table);
+    }
+
+    loop {
+        let sets = thread_pool.find_candidates_from(table.clone(), from)?;
+        let primes: Vec<u64> = sets.iter().flat_map(CandidateSet::iter).collect();
+        if n - below <= primes.len() as u64 {
+            return Ok(Some(primes[(n - below - 1) as usize]));
+        }
+        below += primes.len() as u64;
+        from = sets.last().map_or(from, CandidateSet::to);
+    }
+}
+
+/// Every prime up to at least `limit`, ascending. Read from the primes file as far
+/// as it reaches, and sieved by the thread pool above it.
+fn primes_up_to(thread_pool: &ThreadPool,
+                index: Option<&mut fs::PrimesIndex>,
+                limit: u64)
+                -> Result<Vec<u64>, SieveError> {
+    let (mut primes, mut covered) = match index {
+        Some(index) => {
+            let covered = index.header().sieve_limit.min(limit.saturating_add(1));
+            (index.primes_between(0, covered)?, covered)
+        }
+        None => (Vec::new(), 0),
+    };
+    if primes.len() < math::init_primes().len() {
+        primes = math::init_primes();
+        covered = primes[primes.len() - 1] + 1;
+    }
+
+    while covered <= limit {
+        let sets = thread_pool.find_candidates_from(primes.clone(), covered)?;
+        covered = sets.last().map_or(covered, CandidateSet::to);
+        primes.extend(sets.iter().flat_map(CandidateSet::iter));
+    }
+    Ok(primes)
+}
+
+/// Saves the primes to the primes file in the config.
+pub fn save(primes: &[u64], config: &Config) -> Result<(), SieveError> {
+    fs::save_primes_as(primes, config.file.clone(), config.encoding())?;
+    Ok(())
+}
+
+impl Display for SieveError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            SieveError::PrimesFileEmpty => {
+                write!(f,
+                       "The primes file was loaded but didn't contain any numbers.")
+            }
+            SieveError::File(ref err) => write!(f, "{}", err),
+            SieveError::Thread(ref error) => write!(f, "Error in thread pool\n\t{}", error),
+            SieveError::Limit(ref msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<FileError> for SieveError {
+    fn from(err: FileError) -> SieveError {
+        SieveError::File(err)
+    }
+}
+
+impl From<ThreadPoolError> for SieveError {
+    fn from(err: ThreadPoolError) -> SieveError {
+        SieveError::Thread(err)
+    }
+}
@@ -0,0 +1,28 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::Error as IOError;
+use std::convert::From;
+
+pub enum FileError {
+    IO(IOError),
+    /// The file is a primes file but its content does not match its checksums or
+    /// its header.
+    Corrupted(String),
+    /// The file is not a primes file, or one written in a format we cannot read.
+    Unsupported(String),
+}
+
+impl Display for FileError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            FileError::IO(ref err) => write!(f, "IO Error: \n\t{}", err),
+            FileError::Corrupted(ref msg) => write!(f, "The primes file is corrupted: {}", msg),
+            FileError::Unsupported(ref msg) => write!(f, "Cannot read the primes file: {}", msg),
+        }
+    }
+}
+
+impl From<IOError> for FileError {
+    fn from(err: IOError) -> FileError {
+        FileError::IO(err)
+    }
+}
@@ -0,0 +1,201 @@
+// Layout of the primes file, all numbers little endian:
+//
+//   header                  HEADER_SIZE bytes
+//   block header            BLOCK_HEADER_SIZE bytes
+//   block payload           `len` bytes holding `count` primes
+//   block header
+//   ...
+//   copy of the header      HEADER_SIZE bytes, past `length`, see `append_primes`
+//
+// Both header sizes are multiples of 8 so raw u64 payloads stay aligned. Version 1
+// files have no `length` in their header; it is worked out from their blocks, and
+// the next save writes a version 2 header.
+
+use std::vec::Vec;
+use fs::checksum::crc32;
+use fs::errors::FileError;
+use fs::serializer::{serialize_u32, serialize_u64, deserialize_u32_at, deserialize_u64_at,
+                     deserialize_buf, serialize_deltas, deserialize_deltas};
+
+pub const MAGIC: &[u8; 8] = b"PRIMESIV";
+pub const VERSION: u32 = 2;
+/// The oldest version that can still be read.
+pub const FIRST_VERSION: u32 = 1;
+pub const HEADER_SIZE: usize = 64;
+pub const BLOCK_HEADER_SIZE: usize = 16;
+
+// Number of primes per block.
+pub const BLOCK_SIZE: u32 = 65536;
+
+pub const LITTLE_ENDIAN: u8 = 0;
+
+pub enum Encoding {
+    /// Every prime as a full u64.
+    Raw,
+    /// Gaps between primes as varints, see `serialize_deltas`. Every block starts
+    /// from scratch so it can be decoded on its own.
+    DeltaVarint,
+}
+
+impl Encoding {
+    fn id(&self) -> u8 {
+        match *self {
+            Encoding::Raw => 0,
+            Encoding::DeltaVarint => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Encoding> {
+        match id {
+            0 => Some(Encoding::Raw),
+            1 => Some(Encoding::DeltaVarint),
+            _ => None,
+        }
+    }
+}
+
+pub struct Header {
+    pub encoding: Encoding,
+    pub block_size: u32,
+    /// Number of primes in the file.
+    pub count: u64,
+    pub highest_prime: u64,
+    /// Every prime below the limit is in the file.
+    pub sieve_limit: u64,
+    /// Number of bytes that belong to the file. Anything after them is left over
+    /// from an interrupted save. 0 for version 1 files until `read_header` has
+    /// measured them.
+    pub length: u64,
+}
+
+impl Header {
+    pub fn new(encoding: Encoding) -> Header {
+        Header {
+            encoding,
+            block_size: BLOCK_SIZE,
+            count: 0,
+            highest_prime: 0,
+            sieve_limit: 0,
+            length: HEADER_SIZE as u64,
+        }
+    }
+
+    pub fn serialize(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0u8; HEADER_SIZE];
+        buf[0..8].copy_from_slice(MAGIC);
+        buf[8..12].copy_from_slice(&serialize_u32(VERSION));
+        buf[12] = LITTLE_ENDIAN;
+        buf[13] = self.encoding.id();
+        buf[16..20].copy_from_slice(&serialize_u32(self.block_size));
+        buf[24..32].copy_from_slice(&serialize_u64(self.count));
+        buf[32..40].copy_from_slice(&serialize_u64(self.highest_prime));
+        buf[40..48].copy_from_slice(&serialize_u64(self.sieve_limit));
+        buf[48..56].copy_from_slice(&serialize_u64(self.length));
+        let checksum = crc32(&buf[..HEADER_SIZE - 4]);
+        buf[HEADER_SIZE - 4..].copy_from_slice(&serialize_u32(checksum));
+        buf
+    }
+
+    pub fn deserialize(buf: &[u8; HEADER_SIZE]) -> Result<Header, FileError> {
+        if &buf[0..8] != MAGIC {
+            return Err(FileError::Unsupported("It does not start with a primes file header."
+                .to_string()));
+        }
+        if crc32(&buf[..HEADER_SIZE - 4]) != deserialize_u32_at(buf, HEADER_SIZE - 4) {
+            return Err(FileError::Corrupted("The header checksum does not match.".to_string()));
+        }
+
+        let version = deserialize_u32_at(buf, 8);
+        if !(FIRST_VERSION..=VERSION).contains(&version) {
+            return Err(FileError::Unsupported(format!("Unknown format version {}.", version)));
+        }
+        if buf[12] != LITTLE_ENDIAN {
+            return Err(FileError::Unsupported("Only little endian files can be read."
+                .to_string()));
+        }
+        let encoding = match Encoding::from_id(buf[13]) {
+            Some(encoding) => encoding,
+            None => return Err(FileError::Unsupported(format!("Unknown encoding {}.", buf[13]))),
+        };
+
+        Ok(Header {
+            encoding,
+            block_size: deserialize_u32_at(buf, 16),
+            count: deserialize_u64_at(buf, 24),
+            highest_prime: deserialize_u64_at(buf, 32),
+            sieve_limit: deserialize_u64_at(buf, 40),
+            length: if version == FIRST_VERSION { 0 } else { deserialize_u64_at(buf, 48) },
+        })
+    }
+}
+
+pub struct BlockHeader {
+    /// Number of primes in the block.
+    pub count: u32,
+    /// Length of the payload in bytes.
+    pub len: u32,
+    pub checksum: u32,
+}
+
+impl BlockHeader {
+    pub fn for_payload(count: u32, payload: &[u8]) -> BlockHeader {
+        BlockHeader {
+            count,
+            len: payload.len() as u32,
+            checksum: crc32(payload),
+        }
+    }
+
+    pub fn serialize(&self) -> [u8; BLOCK_HEADER_SIZE] {
+        let mut buf = [0u8; BLOCK_HEADER_SIZE];
+        buf[0..4].copy_from_slice(&serialize_u32(self.count));
+        buf[4..8].copy_from_slice(&serialize_u32(self.len));
+        buf[8..12].copy_from_slice(&serialize_u32(self.checksum));
+        buf
+    }
+
+    pub fn deserialize(buf: &[u8; BLOCK_HEADER_SIZE]) -> BlockHeader {
+        BlockHeader {
+            count: deserialize_u32_at(buf, 0),
+            len: deserialize_u32_at(buf, 4),
+            checksum: deserialize_u32_at(buf, 8),
+        }
+    }
+
+    pub fn matches(&self, payload: &[u8]) -> bool {
+        payload.len() == self.len as usize && crc32(payload) == self.checksum
+    }
+}
+
+/// Serializes the primes as one block: block header followed by the payload.
+pub fn encode_block(encoding: &Encoding, primes: &[u64]) -> Result<Vec<u8>, FileError> {
+    let payload: Vec<u8> = match *encoding {
+        Encoding::Raw => primes.iter().flat_map(|&p| serialize_u64(p).to_vec()).collect(),
+        Encoding::DeltaVarint => {
+            match serialize_deltas(primes) {
+                Some(payload) => payload,
+                None => {
+                    return Err(FileError::Unsupported("Only ascending primes can be delta \
+                                                       encoded."
+                        .to_string()))
+                }
+            }
+        }
+    };
+
+    let header = BlockHeader::for_payload(primes.len() as u32, &payload);
+    let mut block = Vec::with_capacity(BLOCK_HEADER_SIZE + payload.len());
+    block.extend_from_slice(&header.serialize());
+    block.extend_from_slice(&payload);
+    Ok(block)
+}
+
+/// Decodes the payload of a block of `count` primes. None if the payload does not
+/// hold exactly that many primes.
+pub fn decode_block(encoding: &Encoding, count: usize, payload: &[u8]) -> Option<Vec<u64>> {
+    match *encoding {
+        Encoding::Raw if payload.len() == count * 8 => Some(deserialize_buf(payload, payload.len())),
+        Encoding::Raw => None,
+        Encoding::DeltaVarint => deserialize_deltas(payload, count),
+    }
+}
@@ -1,76 +1,510 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Result, Read, Write};
-use fs::serializer::{deserialize_buf, serialize_u64};
-use config::MAX_MEM_USAGE;
-
-const PAGE_SIZE: usize = MAX_MEM_USAGE / 2;
-const BUF_SIZE: usize = 4194304;
+use std::fs::{rename, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::result::Result;
+use fs::errors::FileError;
+use fs::format::{encode_block, decode_block, BlockHeader, Encoding, Header, BLOCK_HEADER_SIZE,
+                 HEADER_SIZE};
+use fs::serializer::MAX_VARINT_LEN;
+use config::Config;
 
 pub struct PrimesPagination {
     pub file: File,
-    pub position: usize,
+    pub position: usize, // byte offset of the next block
+    header: Header,
+    page_size: usize,
+    read: u64,
+    failed: bool,
+}
+
+impl PrimesPagination {
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn read_block(&mut self) -> Result<Vec<u64>, FileError> {
+        let (primes, len) = read_block(&mut self.file, &self.header, self.position as u64)?;
+
+        self.read += primes.len() as u64;
+        if self.read > self.header.count {
+            return Err(corrupted_block(self.position as u64, "holds more primes than counted"));
+        }
+        self.position += len;
+
+        Ok(primes)
+    }
+}
+
+/// Reads and checks the block at the current position of the file, which is
+/// `position` bytes into it. Returns the primes and the size of the block in bytes.
+pub fn read_block(file: &mut File,
+                  header: &Header,
+                  position: u64)
+                  -> Result<(Vec<u64>, usize), FileError> {
+    let mut head = [0u8; BLOCK_HEADER_SIZE];
+    read_exact(file, &mut head, position)?;
+    let block = BlockHeader::deserialize(&head);
+    check_block_size(header, &block, position)?;
+
+    let mut payload = vec![0u8; block.len as usize];
+    read_exact(file, &mut payload, position)?;
+    if !block.matches(&payload) {
+        return Err(corrupted_block(position, "does not match its checksum"));
+    }
+
+    match decode_block(&header.encoding, block.count as usize, &payload) {
+        Some(primes) => Ok((primes, BLOCK_HEADER_SIZE + payload.len())),
+        None => Err(corrupted_block(position, "does not decode to its count of primes")),
+    }
+}
+
+/// Rejects block headers that cannot belong to the file, before their payload is
+/// read.
+pub fn check_block_size(header: &Header, block: &BlockHeader, position: u64) -> Result<(), FileError> {
+    // No prime takes more than a whole varint, which is longer than a raw u64.
+    let too_big = block.len as u64 > block.count as u64 * MAX_VARINT_LEN as u64;
+    if block.count == 0 || block.count > header.block_size || too_big {
+        return Err(corrupted_block(position, "has an impossible size"));
+    }
+    Ok(())
+}
+
+fn read_exact(file: &mut File, buf: &mut [u8], position: u64) -> Result<(), FileError> {
+    match file.read_exact(buf) {
+        Ok(()) => Ok(()),
+        Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
+            Err(corrupted_block(position, "is cut off by the end of the file"))
+        }
+        Err(err) => Err(FileError::IO(err)),
+    }
+}
+
+pub fn corrupted_block(position: u64, what: &str) -> FileError {
+    FileError::Corrupted(format!("The block at byte {} {}.", position, what))
 }
 
 impl Iterator for PrimesPagination {
-    type Item = Vec<u64>;
+    type Item = Result<Vec<u64>, FileError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut buf = [0u8; BUF_SIZE];
-        let mut primes = Vec::with_capacity(PAGE_SIZE);
+        if self.failed {
+            return None;
+        }
 
-        let mut read_total = 0usize;
+        let mut primes = Vec::new();
+        while primes.len() < self.page_size && self.read < self.header.count {
+            match self.read_block() {
+                Ok(mut block) => primes.append(&mut block),
+                Err(err) => {
+                    self.failed = true;
+                    return Some(Err(err));
+                }
+            }
+        }
 
-        while read_total < PAGE_SIZE {
-            match self.file.read(&mut buf) {
-                Ok(read) => {
-                    if read == 0 {
-                        break;
-                    }
-                    println!("Read {}", read);
-                    read_total += read;
-                    if read_total > PAGE_SIZE {
-                        let delta = read_total - PAGE_SIZE;
-                        deserialize_to_vec(&buf, read - delta, &mut primes)
-                    } else {
-                        deserialize_to_vec(&buf, read, &mut primes)
+        if primes.is_empty() {
+            None
+        } else {
+            Some(Ok(primes))
+        }
+    }
+}
+
+/// Pages through the primes file with the page size of the default config.
+pub fn load_primes(file_name: String) -> Result<PrimesPagination, FileError> {
+    load_primes_paged(file_name, Config::default().page_size())
+}
+
+/// Like `load_primes` but with pages of (at least) `page_size` primes. Pages always
+/// hold whole blocks.
+pub fn load_primes_paged(file_name: String,
+                         page_size: usize)
+                         -> Result<PrimesPagination, FileError> {
+    let mut file = File::open(file_name)?;
+    let header = read_header(&mut file)?;
+    Ok(PrimesPagination {
+        file,
+        position: HEADER_SIZE,
+        header,
+        page_size,
+        read: 0,
+        failed: false,
+    })
+}
+
+/// Reads the header of the primes file and leaves the file at its first block. A
+/// header torn by a crash while it was rewritten is replaced by the copy that
+/// `append_primes` leaves after the blocks.
+pub fn read_header(file: &mut File) -> Result<Header, FileError> {
+    let mut buf = [0u8; HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = match file.read_exact(&mut buf) {
+        Ok(()) => {
+            match Header::deserialize(&buf) {
+                Ok(header) => header,
+                Err(err) => {
+                    match read_header_copy(file)? {
+                        Some(header) => header,
+                        None => return Err(err),
                     }
                 }
-                Err(_) => return None,
             }
         }
+        Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
+            return Err(FileError::Unsupported("It is too short to hold a primes file header."
+                .to_string()))
+        }
+        Err(err) => return Err(FileError::IO(err)),
+    };
+
+    if header.length == 0 {
+        header.length = end_of_blocks(file, &header)?;
+    }
+    file.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+    Ok(header)
+}
+
+/// The copy of the header at the end of the file, if there is one that covers
+/// everything before it.
+fn read_header_copy(file: &mut File) -> Result<Option<Header>, FileError> {
+    let size = file.metadata()?.len();
+    if size < 2 * HEADER_SIZE as u64 {
+        return Ok(None);
+    }
 
-        Some(primes)
+    let mut buf = [0u8; HEADER_SIZE];
+    file.seek(SeekFrom::Start(size - HEADER_SIZE as u64))?;
+    file.read_exact(&mut buf)?;
+    match Header::deserialize(&buf) {
+        Ok(header) if header.length == size - HEADER_SIZE as u64 => Ok(Some(header)),
+        _ => Ok(None),
     }
+}
 
+/// Byte offset after the blocks that hold the primes of the header, for version 1
+/// headers that do not say.
+fn end_of_blocks(file: &mut File, header: &Header) -> Result<u64, FileError> {
+    let mut position = HEADER_SIZE as u64;
+    let mut count = 0;
+    while count < header.count {
+        let mut head = [0u8; BLOCK_HEADER_SIZE];
+        file.seek(SeekFrom::Start(position))?;
+        read_exact(file, &mut head, position)?;
+        let block = BlockHeader::deserialize(&head);
+        check_block_size(header, &block, position)?;
 
+        count += block.count as u64;
+        position += (BLOCK_HEADER_SIZE + block.len as usize) as u64;
+    }
+    Ok(position)
 }
 
+/// Adds the primes above the highest prime in the file to it, creating the file if
+/// needed. The primes must be ascending. New files get the encoding of the default
+/// config.
+pub fn save_primes(primes: &[u64], fname: String) -> Result<(), FileError> {
+    save_primes_as(primes, fname, Config::default().encoding())
+}
 
-fn deserialize_to_vec(buf: &[u8], read: usize, out_vec: &mut Vec<u64>) {
-    let mut vec = deserialize_buf(&buf, read);
-    out_vec.append(&mut vec);
+/// Like `save_primes`, with the encoding to use if the file is new. Existing files
+/// keep their encoding. Once this returns the primes are saved; the index next to
+/// the file catches up the next time it is opened, or right away with
+/// `update_index`, which reports its own failures.
+pub fn save_primes_as(primes: &[u64], fname: String, encoding: Encoding) -> Result<(), FileError> {
+    match OpenOptions::new().read(true).write(true).open(&fname) {
+        Ok(file) => append_primes(file, primes),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => {
+            create_primes_file(primes, &fname, encoding)
+        }
+        Err(err) => Err(FileError::IO(err)),
+    }
 }
 
-pub fn load_primes(file_name: String) -> Result<PrimesPagination> {
-    let file = try!(File::open(file_name));
-    Ok(PrimesPagination {
-        file: file,
-        position: 0,
-    })
+/// Writes the whole file under a temporary name and renames it into place, so the
+/// file either does not exist or is complete.
+fn create_primes_file(primes: &[u64], fname: &str, encoding: Encoding) -> Result<(), FileError> {
+    let tmp_name = format!("{}.tmp", fname);
+    let mut file = File::create(&tmp_name)?;
+    let mut header = Header::new(encoding);
+
+    file.write_all(&header.serialize())?;
+    write_blocks(&mut file, &mut header, primes)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header.serialize())?;
+    file.sync_all()?;
+
+    rename(&tmp_name, fname)?;
+    sync_dir(fname)
 }
 
-pub fn save_primes(primes: &Vec<u64>, fname: String) -> Result<()> {
-    let fname = &fname;
-    let mut file = match OpenOptions::new()
-        .append(true)
-        .open(fname) {
-        Ok(file) => file,
-        Err(_) => try!(File::create(fname)),
+/// Makes a rename into the directory of the file durable.
+#[cfg(unix)]
+fn sync_dir(fname: &str) -> Result<(), FileError> {
+    let dir = match Path::new(fname).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
     };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories cannot be opened to sync them here.
+#[cfg(not(unix))]
+fn sync_dir(_: &str) -> Result<(), FileError> {
+    Ok(())
+}
 
-    for &p in primes {
-        try!(file.write_all(&serialize_u64(p)));
+/// Appends the new primes after the committed end of the file. The blocks and a copy
+/// of the new header behind them are on disk before the header that makes them part
+/// of the file is rewritten, so a crash at any point leaves the file as it was before
+/// or after the save: a torn header gives way to its copy in `read_header`.
+/// Leftovers of an interrupted save, and the copy of the last one, are overwritten.
+fn append_primes(mut file: File, primes: &[u64]) -> Result<(), FileError> {
+    let mut header = read_header(&mut file)?;
+    let known = primes.partition_point(|&p| p <= header.highest_prime);
+    let primes = &primes[known..];
+    if primes.is_empty() {
+        return Ok(());
     }
 
+    file.set_len(header.length)?;
+    file.seek(SeekFrom::Start(header.length))?;
+    write_blocks(&mut file, &mut header, primes)?;
+    file.write_all(&header.serialize())?;
+    file.sync_data()?;
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header.serialize())?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Writes the primes as blocks at the current position and accounts for them in
+/// the header.
+fn write_blocks(file: &mut File, header: &mut Header, primes: &[u64]) -> Result<(), FileError> {
+    for block in primes.chunks(header.block_size as usize) {
+        let encoded = encode_block(&header.encoding, block)?;
+        file.write_all(&encoded)?;
+        header.length += encoded.len() as u64;
+        header.count += block.len() as u64;
+    }
+
+    if let Some(&last) = primes.last() {
+        header.highest_prime = header.highest_prime.max(last);
+        header.sieve_limit = header.highest_prime.saturating_add(1);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{load_primes, load_primes_paged, save_primes, save_primes_as};
+    use fs::errors::FileError;
+    use fs::checksum::crc32;
+    use fs::format::{Encoding, HEADER_SIZE, BLOCK_HEADER_SIZE, VERSION};
+    use fs::serializer::{serialize_u32, serialize_u64};
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+    use test_util::{clean_up, temp_file};
+
+    fn load_all(fname: &str, page_size: usize) -> Result<Vec<Vec<u64>>, FileError> {
+        load_primes_paged(fname.to_string(), page_size)?.collect()
+    }
+
+    fn overwrite(fname: &str, at: u64, bytes: &[u8]) {
+        let mut file = OpenOptions::new().write(true).open(fname).unwrap();
+        file.seek(SeekFrom::Start(at)).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn round_trips_over_blocks_and_pages() {
+        let fname = temp_file("round_trip");
+        let numbers: Vec<u64> = (0..150_000).map(|i| 2 * i + 1).collect();
+        assert!(save_primes(&numbers[..70_000], fname.clone()).is_ok());
+        assert!(save_primes(&numbers[70_000..], fname.clone()).is_ok());
+
+        let pager = match load_primes(fname.clone()) {
+            Ok(pager) => pager,
+            Err(err) => panic!("{}", err),
+        };
+        assert_eq!(pager.header().count, 150_000);
+        assert_eq!(pager.header().highest_prime, 299_999);
+
+        let pages = match load_all(&fname, 100_000) {
+            Ok(pages) => pages,
+            Err(err) => panic!("{}", err),
+        };
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages.concat(), numbers);
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn round_trips_compressed() {
+        let fname = temp_file("compressed");
+        let numbers: Vec<u64> = (0..150_000).map(|i| 6 * i + 5).collect();
+        assert!(save_primes_as(&[2, 3], fname.clone(), Encoding::DeltaVarint).is_ok());
+        assert!(save_primes_as(&numbers, fname.clone(), Encoding::Raw).is_ok());
+
+        let pages = match load_all(&fname, 100_000) {
+            Ok(pages) => pages,
+            Err(err) => panic!("{}", err),
+        };
+        assert_eq!(pages.concat(), [vec![2, 3], numbers].concat());
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn detects_corrupted_compressed_block() {
+        let fname = temp_file("corrupted_compressed");
+        assert!(save_primes_as(&[2, 3, 5, 7], fname.clone(), Encoding::DeltaVarint).is_ok());
+        overwrite(&fname, (HEADER_SIZE + BLOCK_HEADER_SIZE + 2) as u64, &[200]);
+
+        match load_all(&fname, 10) {
+            Err(FileError::Corrupted(_)) => {}
+            _ => panic!("corrupted block was not detected"),
+        }
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn saves_only_new_primes() {
+        let fname = temp_file("incremental");
+        assert!(save_primes(&[2, 3, 5, 7, 11], fname.clone()).is_ok());
+        assert!(save_primes(&[11, 13, 17], fname.clone()).is_ok());
+        assert!(save_primes(&[2, 3, 5], fname.clone()).is_ok());
+
+        match load_all(&fname, 10) {
+            Ok(pages) => assert_eq!(pages.concat(), vec![2, 3, 5, 7, 11, 13, 17]),
+            Err(err) => panic!("{}", err),
+        }
+        assert!(!Path::new(&format!("{}.tmp", fname)).exists());
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn ignores_and_replaces_leftovers_of_interrupted_save() {
+        let fname = temp_file("interrupted");
+        assert!(save_primes(&[2, 3, 5, 7], fname.clone()).is_ok());
+
+        // A save killed after writing part of its blocks but before its header.
+        let mut file = OpenOptions::new().append(true).open(&fname).unwrap();
+        file.write_all(&[1, 0, 0, 0, 8, 0, 0, 0, 9, 9, 9]).unwrap();
+
+        match load_all(&fname, 10) {
+            Ok(pages) => assert_eq!(pages.concat(), vec![2, 3, 5, 7]),
+            Err(err) => panic!("{}", err),
+        }
+
+        assert!(save_primes(&[11, 13], fname.clone()).is_ok());
+        match load_all(&fname, 10) {
+            Ok(pages) => assert_eq!(pages.concat(), vec![2, 3, 5, 7, 11, 13]),
+            Err(err) => panic!("{}", err),
+        }
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn detects_corrupted_block() {
+        let fname = temp_file("corrupted_block");
+        assert!(save_primes(&[2, 3, 5, 7], fname.clone()).is_ok());
+        overwrite(&fname, (HEADER_SIZE + BLOCK_HEADER_SIZE + 8) as u64, &[4]);
+
+        match load_all(&fname, 10) {
+            Err(FileError::Corrupted(_)) => {}
+            _ => panic!("corrupted block was not detected"),
+        }
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn detects_corrupted_header() {
+        let fname = temp_file("corrupted_header");
+        assert!(save_primes(&[2, 3, 5, 7], fname.clone()).is_ok());
+        overwrite(&fname, 24, &[3]);
+
+        match load_primes(fname.clone()) {
+            Err(FileError::Corrupted(_)) => {}
+            _ => panic!("corrupted header was not detected"),
+        }
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn torn_header_gives_way_to_its_copy() {
+        let fname = temp_file("torn_header");
+        assert!(save_primes(&[2, 3, 5, 7], fname.clone()).is_ok());
+        assert!(save_primes(&[11, 13], fname.clone()).is_ok());
+        // Half of the new header made it to disk.
+        overwrite(&fname, 24, &serialize_u64(4));
+
+        match load_all(&fname, 10) {
+            Ok(pages) => assert_eq!(pages.concat(), vec![2, 3, 5, 7, 11, 13]),
+            Err(err) => panic!("{}", err),
+        }
+        assert!(save_primes(&[17], fname.clone()).is_ok());
+        match load_all(&fname, 10) {
+            Ok(pages) => assert_eq!(pages.concat(), vec![2, 3, 5, 7, 11, 13, 17]),
+            Err(err) => panic!("{}", err),
+        }
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn appends_to_version_1_files() {
+        let fname = temp_file("version_1");
+        assert!(save_primes(&[2, 3, 5, 7], fname.clone()).is_ok());
+        // Version 1 headers end after the sieve limit.
+        let mut header = load_primes(fname.clone()).ok().unwrap().header().serialize();
+        header[8..12].copy_from_slice(&serialize_u32(1));
+        header[48..56].copy_from_slice(&[0; 8]);
+        let checksum = crc32(&header[..HEADER_SIZE - 4]);
+        header[HEADER_SIZE - 4..].copy_from_slice(&serialize_u32(checksum));
+        overwrite(&fname, 0, &header);
+
+        assert!(save_primes(&[11, 13], fname.clone()).is_ok());
+        let pager = load_primes(fname.clone()).ok().unwrap();
+        assert_eq!(pager.header().count, 6);
+        match load_all(&fname, 10) {
+            Ok(pages) => assert_eq!(pages.concat(), vec![2, 3, 5, 7, 11, 13]),
+            Err(err) => panic!("{}", err),
+        }
+        let mut version = [0u8; 4];
+        let mut file = File::open(&fname).unwrap();
+        file.seek(SeekFrom::Start(8)).unwrap();
+        file.read_exact(&mut version).unwrap();
+        assert_eq!(version, serialize_u32(VERSION));
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn detects_truncated_file() {
+        let fname = temp_file("truncated");
+        assert!(save_primes(&[2, 3, 5, 7], fname.clone()).is_ok());
+        let file = OpenOptions::new().write(true).open(&fname).unwrap();
+        file.set_len((HEADER_SIZE + BLOCK_HEADER_SIZE + 20) as u64).unwrap();
+
+        match load_all(&fname, 10) {
+            Err(FileError::Corrupted(_)) => {}
+            _ => panic!("truncated file was not detected"),
+        }
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn rejects_headerless_file() {
+        let fname = temp_file("headerless");
+        let mut file = File::create(&fname).unwrap();
+        for p in 0..100 {
+            file.write_all(&serialize_u64(p)).unwrap();
+        }
+
+        match load_primes(fname.clone()) {
+            Err(FileError::Unsupported(_)) => {}
+            _ => panic!("headerless file was accepted"),
+        }
+        clean_up(&fname);
+    }
+}
@@ -0,0 +1,484 @@
+// Layout of the index file kept next to the primes file, all numbers little endian:
+//
+//   header                  INDEX_HEADER_SIZE bytes
+//   entry                   ENTRY_SIZE bytes, one per block of the primes file
+//   entry
+//   ...
+//
+// Every entry holds the first prime of a block, its position among all primes and
+// the byte offset of the block, so a lookup is a binary search over the entries
+// followed by reading a single block. The index only ever mirrors the primes file:
+// when it is missing, stale or damaged it is rebuilt from the blocks.
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::result::Result;
+use fs::checksum::crc32;
+use fs::errors::FileError;
+use fs::fs::{read_block, read_header};
+use fs::format::{Header, HEADER_SIZE};
+use fs::serializer::{serialize_u32, serialize_u64, deserialize_u32_at, deserialize_u64_at};
+
+const INDEX_MAGIC: &[u8; 8] = b"PRIMEIDX";
+const INDEX_VERSION: u32 = 1;
+const INDEX_HEADER_SIZE: usize = 40;
+const ENTRY_SIZE: usize = 32;
+
+/// Part of the primes file that the index covers.
+struct IndexHeader {
+    /// Number of primes in the indexed blocks.
+    count: u64,
+    /// Byte offset just after the last indexed block.
+    length: u64,
+    entries: u64,
+}
+
+impl IndexHeader {
+    fn empty() -> IndexHeader {
+        IndexHeader {
+            count: 0,
+            length: HEADER_SIZE as u64,
+            entries: 0,
+        }
+    }
+
+    fn serialize(&self) -> [u8; INDEX_HEADER_SIZE] {
+        let mut buf = [0u8; INDEX_HEADER_SIZE];
+        buf[0..8].copy_from_slice(INDEX_MAGIC);
+        buf[8..12].copy_from_slice(&serialize_u32(INDEX_VERSION));
+        buf[12..20].copy_from_slice(&serialize_u64(self.count));
+        buf[20..28].copy_from_slice(&serialize_u64(self.length));
+        buf[28..36].copy_from_slice(&serialize_u64(self.entries));
+        let checksum = crc32(&buf[..INDEX_HEADER_SIZE - 4]);
+        buf[INDEX_HEADER_SIZE - 4..].copy_from_slice(&serialize_u32(checksum));
+        buf
+    }
+
+    /// None if the header is not one we wrote.
+    fn deserialize(buf: &[u8; INDEX_HEADER_SIZE]) -> Option<IndexHeader> {
+        if &buf[0..8] != INDEX_MAGIC || deserialize_u32_at(buf, 8) != INDEX_VERSION ||
+           crc32(&buf[..INDEX_HEADER_SIZE - 4]) != deserialize_u32_at(buf, INDEX_HEADER_SIZE - 4) {
+            return None;
+        }
+
+        Some(IndexHeader {
+            count: deserialize_u64_at(buf, 12),
+            length: deserialize_u64_at(buf, 20),
+            entries: deserialize_u64_at(buf, 28),
+        })
+    }
+}
+
+struct Entry {
+    first_prime: u64,
+    /// Number of primes before the block.
+    ordinal: u64,
+    offset: u64,
+}
+
+impl Entry {
+    fn serialize(&self) -> [u8; ENTRY_SIZE] {
+        let mut buf = [0u8; ENTRY_SIZE];
+        buf[0..8].copy_from_slice(&serialize_u64(self.first_prime));
+        buf[8..16].copy_from_slice(&serialize_u64(self.ordinal));
+        buf[16..24].copy_from_slice(&serialize_u64(self.offset));
+        let checksum = crc32(&buf[..24]);
+        buf[24..28].copy_from_slice(&serialize_u32(checksum));
+        buf
+    }
+
+    fn deserialize(buf: &[u8; ENTRY_SIZE]) -> Option<Entry> {
+        if crc32(&buf[..24]) != deserialize_u32_at(buf, 24) {
+            return None;
+        }
+
+        Some(Entry {
+            first_prime: deserialize_u64_at(buf, 0),
+            ordinal: deserialize_u64_at(buf, 8),
+            offset: deserialize_u64_at(buf, 16),
+        })
+    }
+}
+
+fn index_name(fname: &str) -> String {
+    format!("{}.idx", fname)
+}
+
+/// Indexes the blocks of the primes file that are not indexed yet. An index that
+/// does not belong to the file any more is rebuilt from scratch.
+pub fn update_index(fname: &str) -> Result<(), FileError> {
+    let mut primes = File::open(fname)?;
+    let header = read_header(&mut primes)?;
+    let mut index = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+        .open(index_name(fname))?;
+
+    let mut covered = match read_index_header(&mut index)? {
+        Some(covered) if covered.count <= header.count && covered.length <= header.length => {
+            covered
+        }
+        _ => IndexHeader::empty(),
+    };
+    if !ends_at_last_entry(&mut index, &mut primes, &header, &covered)? {
+        covered = IndexHeader::empty();
+    }
+    index.set_len(INDEX_HEADER_SIZE as u64 + covered.entries * ENTRY_SIZE as u64)?;
+    index.seek(SeekFrom::End(0))?;
+    primes.seek(SeekFrom::Start(covered.length))?;
+    while covered.count < header.count {
+        let (block, len) = read_block(&mut primes, &header, covered.length)?;
+        let entry = Entry {
+            first_prime: block[0],
+            ordinal: covered.count,
+            offset: covered.length,
+        };
+        index.write_all(&entry.serialize())?;
+
+        covered.count += block.len() as u64;
+        covered.length += len as u64;
+        covered.entries += 1;
+    }
+    if covered.count != header.count || covered.length != header.length {
+        return Err(FileError::Corrupted("The blocks do not add up to the header.".to_string()));
+    }
+
+    index.seek(SeekFrom::Start(0))?;
+    index.write_all(&covered.serialize())?;
+    Ok(())
+}
+
+/// Whether the last entry of the index is a block of the primes file that ends where
+/// the index says it does. Catches indexes left over from a replaced primes file.
+fn ends_at_last_entry(index: &mut File,
+                      primes: &mut File,
+                      header: &Header,
+                      covered: &IndexHeader)
+                      -> Result<bool, FileError> {
+    if covered.entries == 0 {
+        return Ok(covered.count == 0);
+    }
+
+    let entry = match read_entry(index, covered.entries - 1)? {
+        Some(entry) => entry,
+        None => return Ok(false),
+    };
+    primes.seek(SeekFrom::Start(entry.offset))?;
+    match read_block(primes, header, entry.offset) {
+        Ok((block, len)) => {
+            Ok(block[0] == entry.first_prime && entry.ordinal + block.len() as u64 == covered.count &&
+               entry.offset + len as u64 == covered.length)
+        }
+        Err(FileError::Corrupted(_)) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn read_entry(index: &mut File, i: u64) -> Result<Option<Entry>, FileError> {
+    let mut buf = [0u8; ENTRY_SIZE];
+    index.seek(SeekFrom::Start(INDEX_HEADER_SIZE as u64 + i * ENTRY_SIZE as u64))?;
+    index.read_exact(&mut buf)?;
+    Ok(Entry::deserialize(&buf))
+}
+
+/// Reads the header of the index. None if the file holds no valid index.
+fn read_index_header(index: &mut File) -> Result<Option<IndexHeader>, FileError> {
+    let size = index.metadata()?.len();
+    if size < INDEX_HEADER_SIZE as u64 {
+        return Ok(None);
+    }
+
+    let mut buf = [0u8; INDEX_HEADER_SIZE];
+    index.seek(SeekFrom::Start(0))?;
+    index.read_exact(&mut buf)?;
+    match IndexHeader::deserialize(&buf) {
+        Some(covered) if covered.entries <= (size - INDEX_HEADER_SIZE as u64) / ENTRY_SIZE as u64 => {
+            Ok(Some(covered))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Random access to the primes file through its index. Every lookup takes a binary
+/// search over the entries of the index and a single block read.
+pub struct PrimesIndex {
+    primes: File,
+    header: Header,
+    index: File,
+    entries: u64,
+}
+
+/// Opens the primes file with its index, updating the index first if needed.
+pub fn open_index(fname: String) -> Result<PrimesIndex, FileError> {
+    let mut primes = File::open(&fname)?;
+    let header = read_header(&mut primes)?;
+
+    let mut index = match File::open(index_name(&fname)) {
+        Ok(index) => index,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => {
+            update_index(&fname)?;
+            File::open(index_name(&fname))?
+        }
+        Err(err) => return Err(FileError::IO(err)),
+    };
+    let mut covered = read_index_header(&mut index)?;
+    if covered.as_ref().is_none_or(|covered| covered.count != header.count) {
+        update_index(&fname)?;
+        covered = read_index_header(&mut index)?;
+    }
+    PrimesIndex::covering(primes, header, index, covered)
+}
+
+/// Opens the primes file with its index as they are, without creating or updating
+/// the index. An index that does not cover every prime of the file is corrupted.
+pub fn open_index_read_only(fname: String) -> Result<PrimesIndex, FileError> {
+    let mut primes = File::open(&fname)?;
+    let header = read_header(&mut primes)?;
+    let mut index = File::open(index_name(&fname))?;
+    let covered = read_index_header(&mut index)?;
+    PrimesIndex::covering(primes, header, index, covered)
+}
+
+impl PrimesIndex {
+    fn covering(primes: File,
+                header: Header,
+                index: File,
+                covered: Option<IndexHeader>)
+                -> Result<PrimesIndex, FileError> {
+        match covered {
+            Some(ref covered) if covered.count == header.count => {
+                Ok(PrimesIndex {
+                    primes,
+                    header,
+                    index,
+                    entries: covered.entries,
+                })
+            }
+            _ => {
+                Err(FileError::Corrupted("The index does not match the primes file.".to_string()))
+            }
+        }
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The n-th prime, counting from 1. None if the file holds fewer primes.
+    pub fn nth(&mut self, n: u64) -> Result<Option<u64>, FileError> {
+        if n == 0 || n > self.header.count {
+            return Ok(None);
+        }
+
+        let position = n - 1;
+        let entry = match self.last_entry_where(|entry| entry.ordinal <= position)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let (block, _) = self.block_at(entry.offset)?;
+        Ok(block.get((position - entry.ordinal) as usize).cloned())
+    }
+
+    /// Number of primes up to and including `x`. None if the file does not reach
+    /// `x`, since the primes above its sieve limit are unknown.
+    pub fn count_up_to(&mut self, x: u64) -> Result<Option<u64>, FileError> {
+        if x >= self.header.sieve_limit {
+            return Ok(None);
+        }
+
+        match self.last_entry_where(|entry| entry.first_prime <= x)? {
+            Some(entry) => {
+                let (block, _) = self.block_at(entry.offset)?;
+                Ok(Some(entry.ordinal + block.partition_point(|&p| p <= x) as u64))
+            }
+            None => Ok(Some(0)),
+        }
+    }
+
+    /// The primes of the file in `[from, to)`, none if the range is empty.
+    pub fn primes_between(&mut self, from: u64, to: u64) -> Result<Vec<u64>, FileError> {
+        if to <= from {
+            return Ok(Vec::new());
+        }
+        let mut offset = match self.last_entry_where(|entry| entry.first_prime <= from)? {
+            Some(entry) => entry.offset,
+            None => HEADER_SIZE as u64,
+        };
+
+        let mut primes = Vec::new();
+        while offset < self.header.length {
+            let (block, len) = self.block_at(offset)?;
+            let start = block.partition_point(|&p| p < from);
+            let end = block.partition_point(|&p| p < to);
+            primes.extend_from_slice(&block[start..end]);
+            if end < block.len() {
+                break;
+            }
+            offset += len as u64;
+        }
+
+        Ok(primes)
+    }
+
+    /// The last entry that `pred` holds for, which must hold for a prefix of the
+    /// entries.
+    fn last_entry_where<F>(&mut self, pred: F) -> Result<Option<Entry>, FileError>
+        where F: Fn(&Entry) -> bool
+    {
+        let (mut low, mut high) = (0, self.entries);
+        let mut found = None;
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let entry = self.entry(middle)?;
+            if pred(&entry) {
+                low = middle + 1;
+                found = Some(entry);
+            } else {
+                high = middle;
+            }
+        }
+        Ok(found)
+    }
+
+    fn entry(&mut self, i: u64) -> Result<Entry, FileError> {
+        match read_entry(&mut self.index, i)? {
+            Some(entry) => Ok(entry),
+            None => Err(FileError::Corrupted(format!("Entry {} of the index does not match its \
+                                                      checksum.",
+                                                     i))),
+        }
+    }
+
+    fn block_at(&mut self, offset: u64) -> Result<(Vec<u64>, usize), FileError> {
+        self.primes.seek(SeekFrom::Start(offset))?;
+        read_block(&mut self.primes, &self.header, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open_index, open_index_read_only, index_name, update_index, PrimesIndex,
+                INDEX_HEADER_SIZE};
+    use fs::errors::FileError;
+    use fs::fs::save_primes_as;
+    use fs::format::Encoding;
+    use std::fs::{create_dir, remove_dir, remove_file, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use test_util::{clean_up, temp_file};
+
+    fn open(fname: &str) -> PrimesIndex {
+        match open_index(fname.to_string()) {
+            Ok(index) => index,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    fn numbers() -> Vec<u64> {
+        (0..200_000).map(|i| 2 * i + 3).collect()
+    }
+
+    #[test]
+    fn finds_nth_across_blocks() {
+        for encoding in [Encoding::Raw, Encoding::DeltaVarint] {
+            let fname = temp_file("nth");
+            let numbers = numbers();
+            assert!(save_primes_as(&numbers, fname.clone(), encoding).is_ok());
+
+            let mut index = open(&fname);
+            for &n in &[1, 2, 65_536, 65_537, 131_073, 200_000] {
+                assert_eq!(index.nth(n).ok().unwrap(), Some(numbers[n as usize - 1]));
+            }
+            assert_eq!(index.nth(0).ok().unwrap(), None);
+            assert_eq!(index.nth(200_001).ok().unwrap(), None);
+            clean_up(&fname);
+        }
+    }
+
+    #[test]
+    fn counts_and_ranges_near_x() {
+        let fname = temp_file("near");
+        let numbers = numbers();
+        assert!(save_primes_as(&numbers, fname.clone(), Encoding::Raw).is_ok());
+
+        let mut index = open(&fname);
+        assert_eq!(index.count_up_to(2).ok().unwrap(), Some(0));
+        assert_eq!(index.count_up_to(131_075).ok().unwrap(), Some(65_537));
+        assert_eq!(index.count_up_to(131_076).ok().unwrap(), Some(65_537));
+        assert_eq!(index.count_up_to(400_002).ok().unwrap(), None);
+
+        let between = index.primes_between(131_060, 131_090).ok().unwrap();
+        assert_eq!(between,
+                   (131_060..131_090).filter(|n| n % 2 == 1).collect::<Vec<u64>>());
+        assert_eq!(index.primes_between(0, 9).ok().unwrap(), vec![3, 5, 7]);
+        assert!(index.primes_between(131_061, 131_061).ok().unwrap().is_empty());
+        assert!(index.primes_between(131_090, 131_060).ok().unwrap().is_empty());
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn follows_appends() {
+        let fname = temp_file("appends");
+        let numbers = numbers();
+        assert!(save_primes_as(&numbers[..70_000], fname.clone(), Encoding::Raw).is_ok());
+        assert_eq!(open(&fname).nth(70_001).ok().unwrap(), None);
+
+        assert!(save_primes_as(&numbers, fname.clone(), Encoding::Raw).is_ok());
+        let mut index = open(&fname);
+        assert_eq!(index.nth(70_001).ok().unwrap(), Some(numbers[70_000]));
+        assert_eq!(index.nth(200_000).ok().unwrap(), Some(numbers[199_999]));
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn rebuilds_missing_and_damaged_index() {
+        let fname = temp_file("rebuild");
+        let numbers = numbers();
+        assert!(save_primes_as(&numbers, fname.clone(), Encoding::Raw).is_ok());
+
+        let _ = remove_file(index_name(&fname));
+        assert_eq!(open(&fname).nth(100_000).ok().unwrap(), Some(numbers[99_999]));
+
+        let mut file = OpenOptions::new().write(true).open(index_name(&fname)).unwrap();
+        file.seek(SeekFrom::Start(INDEX_HEADER_SIZE as u64 - 2)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        assert_eq!(open(&fname).nth(100_000).ok().unwrap(), Some(numbers[99_999]));
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn saves_do_not_depend_on_the_index() {
+        let fname = temp_file("unwritable");
+        let numbers = numbers();
+        assert!(save_primes_as(&numbers[..70_000], fname.clone(), Encoding::Raw).is_ok());
+        assert!(update_index(&fname).is_ok());
+
+        // A directory in the way of the index fails its update, but not the save.
+        let _ = remove_file(index_name(&fname));
+        create_dir(index_name(&fname)).unwrap();
+        assert!(save_primes_as(&numbers, fname.clone(), Encoding::Raw).is_ok());
+        assert!(update_index(&fname).is_err());
+
+        remove_dir(index_name(&fname)).unwrap();
+        assert_eq!(open(&fname).nth(200_000).ok().unwrap(), Some(numbers[199_999]));
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn read_only_index_is_left_as_it_is() {
+        let fname = temp_file("read_only");
+        let numbers = numbers();
+        assert!(save_primes_as(&numbers[..70_000], fname.clone(), Encoding::Raw).is_ok());
+        assert!(open_index_read_only(fname.clone()).is_err());
+        assert!(update_index(&fname).is_ok());
+        assert!(open_index_read_only(fname.clone()).is_ok());
+
+        assert!(save_primes_as(&numbers, fname.clone(), Encoding::Raw).is_ok());
+        match open_index_read_only(fname.clone()) {
+            Err(FileError::Corrupted(_)) => {}
+            _ => panic!("an index behind the primes file was taken for up to date"),
+        }
+        match open_index_read_only(fname.clone()) {
+            Err(FileError::Corrupted(_)) => {}
+            _ => panic!("the index was updated"),
+        }
+        clean_up(&fname);
+    }
+}
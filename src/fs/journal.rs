@@ -0,0 +1,366 @@
+// Layout of the checkpoint journal kept next to the primes file while a round is
+// sieved, all numbers little endian:
+//
+//   header                  JOURNAL_HEADER_SIZE bytes
+//   units                   UNIT_SIZE bytes per work unit of the round, then a CRC-32
+//   record                  RECORD_HEADER_SIZE bytes, then the numbers delta encoded
+//   record
+//   ...
+//
+// A record is appended whenever a work unit is done with a stage of the round: stage
+// 0 found the candidates of the unit, stage k sieved them with the k-th page of the
+// primes file. The latest record of a unit is where it resumes. The journal belongs
+// to the round it was started for; once the primes file has moved on, or the pages
+// are cut differently, it is started over. A record that was torn by a crash ends
+// the journal.
+
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::result::Result;
+use fs::checksum::crc32;
+use fs::errors::FileError;
+use fs::serializer::{serialize_u32, serialize_u64, deserialize_u32_at, deserialize_u64_at,
+                     serialize_deltas, deserialize_deltas};
+
+const JOURNAL_MAGIC: &[u8; 8] = b"PRIMEJNL";
+const JOURNAL_VERSION: u32 = 1;
+const JOURNAL_HEADER_SIZE: usize = 48;
+const UNIT_SIZE: usize = 16;
+const RECORD_HEADER_SIZE: usize = 40;
+
+/// The round a journal belongs to.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Round {
+    /// Highest prime and number of primes of the primes file when the round started.
+    pub highest_prime: u64,
+    pub count: u64,
+    /// Page size the primes file is sieved with; the stages are its pages.
+    pub page_size: u64,
+}
+
+impl Round {
+    fn serialize(&self, units: usize) -> [u8; JOURNAL_HEADER_SIZE] {
+        let mut buf = [0u8; JOURNAL_HEADER_SIZE];
+        buf[0..8].copy_from_slice(JOURNAL_MAGIC);
+        buf[8..12].copy_from_slice(&serialize_u32(JOURNAL_VERSION));
+        buf[12..20].copy_from_slice(&serialize_u64(self.highest_prime));
+        buf[20..28].copy_from_slice(&serialize_u64(self.count));
+        buf[28..36].copy_from_slice(&serialize_u64(self.page_size));
+        buf[36..44].copy_from_slice(&serialize_u64(units as u64));
+        let checksum = crc32(&buf[..JOURNAL_HEADER_SIZE - 4]);
+        buf[JOURNAL_HEADER_SIZE - 4..].copy_from_slice(&serialize_u32(checksum));
+        buf
+    }
+
+    /// The round and its number of units. None if the header is not one we wrote.
+    fn deserialize(buf: &[u8]) -> Option<(Round, usize)> {
+        if &buf[0..8] != JOURNAL_MAGIC || deserialize_u32_at(buf, 8) != JOURNAL_VERSION ||
+           crc32(&buf[..JOURNAL_HEADER_SIZE - 4]) !=
+           deserialize_u32_at(buf, JOURNAL_HEADER_SIZE - 4) {
+            return None;
+        }
+
+        let round = Round {
+            highest_prime: deserialize_u64_at(buf, 12),
+            count: deserialize_u64_at(buf, 20),
+            page_size: deserialize_u64_at(buf, 28),
+        };
+        Some((round, deserialize_u64_at(buf, 36) as usize))
+    }
+}
+
+/// A work unit that was done with a stage of the round, and the candidates it had
+/// left then.
+pub struct Checkpoint {
+    pub unit: usize,
+    pub stage: u32,
+    pub from: u64,
+    pub to: u64,
+    pub numbers: Vec<u64>,
+}
+
+impl Checkpoint {
+    fn serialize(&self) -> Result<Vec<u8>, FileError> {
+        let numbers = match serialize_deltas(&self.numbers) {
+            Some(numbers) => numbers,
+            None => {
+                return Err(FileError::Corrupted("Checkpoints need ascending numbers.".to_string()))
+            }
+        };
+
+        let mut buf = vec![0u8; RECORD_HEADER_SIZE];
+        buf[0..4].copy_from_slice(&serialize_u32(self.unit as u32));
+        buf[4..8].copy_from_slice(&serialize_u32(self.stage));
+        buf[8..16].copy_from_slice(&serialize_u64(self.from));
+        buf[16..24].copy_from_slice(&serialize_u64(self.to));
+        buf[24..32].copy_from_slice(&serialize_u64(self.numbers.len() as u64));
+        buf[32..36].copy_from_slice(&serialize_u32(numbers.len() as u32));
+        buf.extend_from_slice(&numbers);
+        let checksum = crc32(&[&buf[..RECORD_HEADER_SIZE - 4], &numbers[..]].concat());
+        buf[RECORD_HEADER_SIZE - 4..RECORD_HEADER_SIZE].copy_from_slice(&serialize_u32(checksum));
+        Ok(buf)
+    }
+
+    /// The record at the start of the buffer and its length. None if the buffer does
+    /// not start with a whole record.
+    fn deserialize(buf: &[u8]) -> Option<(Checkpoint, usize)> {
+        if buf.len() < RECORD_HEADER_SIZE {
+            return None;
+        }
+        let length = RECORD_HEADER_SIZE + deserialize_u32_at(buf, 32) as usize;
+        if buf.len() < length {
+            return None;
+        }
+        let (header, numbers) = (&buf[..RECORD_HEADER_SIZE - 4], &buf[RECORD_HEADER_SIZE..length]);
+        if crc32(&[header, numbers].concat()) != deserialize_u32_at(buf, RECORD_HEADER_SIZE - 4) {
+            return None;
+        }
+
+        let checkpoint = Checkpoint {
+            unit: deserialize_u32_at(buf, 0) as usize,
+            stage: deserialize_u32_at(buf, 4),
+            from: deserialize_u64_at(buf, 8),
+            to: deserialize_u64_at(buf, 16),
+            numbers: deserialize_deltas(numbers, deserialize_u64_at(buf, 24) as usize)?,
+        };
+        Some((checkpoint, length))
+    }
+}
+
+fn journal_name(fname: &str) -> String {
+    format!("{}.journal", fname)
+}
+
+/// The checkpoints of a round in progress, opened for appending.
+pub struct Journal {
+    file: File,
+    units: Vec<(u64, u64)>,
+    checkpoints: Vec<Checkpoint>,
+}
+
+/// Opens the journal of the round next to the primes file. A journal left by an
+/// interrupted run of the same round keeps its units, `[from, to)` each, and its
+/// checkpoints. Any other journal is started over with the given units.
+pub fn open_journal(fname: &str,
+                    round: &Round,
+                    units: Vec<(u64, u64)>)
+                    -> Result<Journal, FileError> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+        .open(journal_name(fname))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+
+    let journal = match read_journal(&buf, round) {
+        Some(intact) => {
+            file.set_len(intact.length as u64)?;
+            file.seek(SeekFrom::End(0))?;
+            Journal {
+                file,
+                units: intact.units,
+                checkpoints: intact.checkpoints,
+            }
+        }
+        None => {
+            let mut buf = round.serialize(units.len()).to_vec();
+            for &(from, to) in &units {
+                buf.extend_from_slice(&serialize_u64(from));
+                buf.extend_from_slice(&serialize_u64(to));
+            }
+            let checksum = crc32(&buf[JOURNAL_HEADER_SIZE..]);
+            buf.extend_from_slice(&serialize_u32(checksum));
+
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&buf)?;
+            file.sync_data()?;
+            Journal {
+                file,
+                units,
+                checkpoints: Vec::new(),
+            }
+        }
+    };
+    Ok(journal)
+}
+
+/// What is left of a journal after a crash.
+struct Intact {
+    units: Vec<(u64, u64)>,
+    checkpoints: Vec<Checkpoint>,
+    /// Bytes up to the end of the last whole record.
+    length: usize,
+}
+
+/// The intact part of a journal of the round. None if the buffer holds no journal of
+/// it.
+fn read_journal(buf: &[u8], round: &Round) -> Option<Intact> {
+    if buf.len() < JOURNAL_HEADER_SIZE {
+        return None;
+    }
+    let no_units = match Round::deserialize(&buf[..JOURNAL_HEADER_SIZE]) {
+        Some((ref written, no_units)) if written == round => no_units,
+        _ => return None,
+    };
+    let units_end = JOURNAL_HEADER_SIZE.checked_add(no_units.checked_mul(UNIT_SIZE)?)?;
+    if buf.len() < units_end + 4 ||
+       crc32(&buf[JOURNAL_HEADER_SIZE..units_end]) != deserialize_u32_at(buf, units_end) {
+        return None;
+    }
+    let units: Vec<(u64, u64)> = buf[JOURNAL_HEADER_SIZE..units_end]
+        .chunks(UNIT_SIZE)
+        .map(|unit| (deserialize_u64_at(unit, 0), deserialize_u64_at(unit, 8)))
+        .collect();
+
+    let mut checkpoints = Vec::new();
+    let mut length = units_end + 4;
+    while let Some((checkpoint, len)) = Checkpoint::deserialize(&buf[length..]) {
+        if units.get(checkpoint.unit) != Some(&(checkpoint.from, checkpoint.to)) {
+            break;
+        }
+        checkpoints.push(checkpoint);
+        length += len;
+    }
+    Some(Intact {
+        units,
+        checkpoints,
+        length,
+    })
+}
+
+impl Journal {
+    /// The work units of the round, `[from, to)` each, ascending.
+    pub fn units(&self) -> &[(u64, u64)] {
+        &self.units
+    }
+
+    /// The checkpoints read when the journal was opened, in the order they were
+    /// written. Empty after the first call.
+    pub fn take_checkpoints(&mut self) -> Vec<Checkpoint> {
+        ::std::mem::take(&mut self.checkpoints)
+    }
+
+    /// Records that the unit is done with the stage and has the numbers left. Synced
+    /// to disk before it returns.
+    pub fn append(&mut self, unit: usize, stage: u32, numbers: Vec<u64>) -> Result<(), FileError> {
+        let (from, to) = self.units[unit];
+        let record = Checkpoint {
+            unit,
+            stage,
+            from,
+            to,
+            numbers,
+        };
+        self.file.write_all(&record.serialize()?)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Removes the journal next to the primes file, once its round is saved. No journal
+/// is fine.
+pub fn remove_journal(fname: &str) -> Result<(), FileError> {
+    match remove_file(journal_name(fname)) {
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{journal_name, open_journal, remove_journal, Journal, Round};
+    use std::fs::OpenOptions;
+    use test_util::{clean_up, temp_file};
+
+    fn round(highest_prime: u64) -> Round {
+        Round {
+            highest_prime,
+            count: 25,
+            page_size: 1000,
+        }
+    }
+
+    fn open(fname: &str, round: &Round, units: Vec<(u64, u64)>) -> Journal {
+        match open_journal(fname, round, units) {
+            Ok(journal) => journal,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    fn append(journal: &mut Journal, unit: usize, stage: u32, numbers: Vec<u64>) {
+        if let Err(err) = journal.append(unit, stage, numbers) {
+            panic!("{}", err);
+        }
+    }
+
+    /// (unit, stage, numbers) of every checkpoint.
+    fn checkpoints(journal: &mut Journal) -> Vec<(usize, u32, Vec<u64>)> {
+        journal.take_checkpoints()
+            .into_iter()
+            .map(|checkpoint| (checkpoint.unit, checkpoint.stage, checkpoint.numbers))
+            .collect()
+    }
+
+    #[test]
+    fn checkpoints_survive_reopening() {
+        let fname = temp_file("reopen");
+        let mut journal = open(&fname, &round(97), vec![(97, 200), (200, 300)]);
+        append(&mut journal, 1, 0, vec![211, 221, 223, 227, 229]);
+        append(&mut journal, 0, 0, vec![101, 103, 107, 109, 113, 121]);
+        append(&mut journal, 0, 1, vec![]);
+        drop(journal);
+
+        // The units of the journal win over the ones a rerun would cut.
+        let mut journal = open(&fname, &round(97), vec![(97, 300)]);
+        assert_eq!(journal.units(), &[(97, 200), (200, 300)]);
+        assert_eq!(checkpoints(&mut journal),
+                   vec![(1, 0, vec![211, 221, 223, 227, 229]),
+                        (0, 0, vec![101, 103, 107, 109, 113, 121]),
+                        (0, 1, vec![])]);
+
+        // Appending goes on after the last record.
+        append(&mut journal, 1, 1, vec![211, 223, 227, 229]);
+        drop(journal);
+        let mut journal = open(&fname, &round(97), vec![]);
+        assert_eq!(checkpoints(&mut journal).len(), 4);
+
+        assert!(remove_journal(&fname).is_ok());
+        assert!(remove_journal(&fname).is_ok());
+    }
+
+    #[test]
+    fn journal_of_another_round_starts_over() {
+        let fname = temp_file("other_round");
+        let mut journal = open(&fname, &round(97), vec![(97, 200)]);
+        append(&mut journal, 0, 0, vec![101, 103]);
+        drop(journal);
+
+        let mut journal = open(&fname, &round(199), vec![(199, 400)]);
+        assert_eq!(journal.units(), &[(199, 400)]);
+        assert!(checkpoints(&mut journal).is_empty());
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn torn_record_ends_the_journal() {
+        let fname = temp_file("torn");
+        let mut journal = open(&fname, &round(97), vec![(97, 200), (200, 300)]);
+        append(&mut journal, 0, 0, vec![101, 103, 107]);
+        append(&mut journal, 1, 0, vec![211, 223, 227]);
+        drop(journal);
+
+        let file = OpenOptions::new().write(true).open(journal_name(&fname)).unwrap();
+        let length = file.metadata().unwrap().len();
+        file.set_len(length - 2).unwrap();
+        drop(file);
+
+        let mut journal = open(&fname, &round(97), vec![]);
+        assert_eq!(checkpoints(&mut journal), vec![(0, 0, vec![101, 103, 107])]);
+        append(&mut journal, 1, 0, vec![211]);
+        drop(journal);
+
+        let mut journal = open(&fname, &round(97), vec![]);
+        assert_eq!(checkpoints(&mut journal),
+                   vec![(0, 0, vec![101, 103, 107]), (1, 0, vec![211])]);
+        clean_up(&fname);
+    }
+}
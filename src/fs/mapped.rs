@@ -0,0 +1,241 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::ops::Range;
+use std::result::Result;
+use memmap2::Mmap;
+use fs::errors::FileError;
+use fs::fs::{check_block_size, corrupted_block, load_primes_paged, read_header, PrimesPagination};
+use config::Config;
+use fs::format::{BlockHeader, Encoding, Header, BLOCK_HEADER_SIZE, HEADER_SIZE};
+
+/// The primes file mapped into memory. Only raw files on little endian machines
+/// can be mapped: their payloads are the primes exactly as they are laid out in
+/// memory, and every payload starts at a multiple of 8 bytes into the file.
+pub struct MappedPrimes {
+    map: Mmap,
+    header: Header,
+    /// Payload of every block, as byte ranges of the map.
+    blocks: Vec<Range<usize>>,
+}
+
+impl MappedPrimes {
+    /// Maps the file and checks every block once, so the views handed out later
+    /// never need to.
+    pub fn open(file_name: String) -> Result<MappedPrimes, FileError> {
+        let mut file = File::open(file_name)?;
+        let header = read_header(&mut file)?;
+        if !MappedPrimes::can_map(&header) {
+            return Err(FileError::Unsupported("Only raw little endian files can be mapped."
+                .to_string()));
+        }
+
+        // The map stays valid as long as nobody truncates the file under us. Saves
+        // only ever append, and cut off nothing below the committed length.
+        let map = unsafe { Mmap::map(&file)? };
+        if (map.len() as u64) < header.length {
+            return Err(FileError::Corrupted("The file is shorter than its header says."
+                .to_string()));
+        }
+
+        let blocks = index_blocks(&map[..header.length as usize], &header)?;
+        Ok(MappedPrimes {
+            map,
+            header,
+            blocks,
+        })
+    }
+
+    pub fn can_map(header: &Header) -> bool {
+        cfg!(target_endian = "little") &&
+        match header.encoding {
+            Encoding::Raw => true,
+            Encoding::DeltaVarint => false,
+        }
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Number of blocks, each of which `block` hands out. The number of primes is
+    /// in the header.
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// The primes of the i-th block, without copying them out of the map.
+    pub fn block(&self, i: usize) -> &[u64] {
+        // Checked in `index_blocks`: the payload is aligned and a multiple of 8 long.
+        let (_, primes, _) = unsafe { self.map[self.blocks[i].clone()].align_to::<u64>() };
+        primes
+    }
+
+    pub fn blocks<'a>(&'a self) -> Box<dyn Iterator<Item = &'a [u64]> + 'a> {
+        Box::new((0..self.blocks.len()).map(move |i| self.block(i)))
+    }
+}
+
+/// Finds the payload of every block and checks it against its block header.
+fn index_blocks(bytes: &[u8], header: &Header) -> Result<Vec<Range<usize>>, FileError> {
+    let mut blocks = Vec::new();
+    let mut position = HEADER_SIZE;
+    let mut count = 0;
+
+    while position < bytes.len() {
+        if bytes.len() - position < BLOCK_HEADER_SIZE {
+            return Err(corrupted_block(position as u64, "is cut off by the end of the file"));
+        }
+        let mut head = [0u8; BLOCK_HEADER_SIZE];
+        head.copy_from_slice(&bytes[position..position + BLOCK_HEADER_SIZE]);
+        let block = BlockHeader::deserialize(&head);
+        check_block_size(header, &block, position as u64)?;
+
+        let start = position + BLOCK_HEADER_SIZE;
+        let end = start + block.len as usize;
+        if end > bytes.len() {
+            return Err(corrupted_block(position as u64, "is cut off by the end of the file"));
+        }
+        let payload = &bytes[start..end];
+        if !block.matches(payload) {
+            return Err(corrupted_block(position as u64, "does not match its checksum"));
+        }
+        if payload.len() != block.count as usize * 8 || !(payload.as_ptr() as usize).is_multiple_of(8) {
+            return Err(corrupted_block(position as u64, "does not hold whole aligned primes"));
+        }
+
+        count += block.count as u64;
+        blocks.push(start..end);
+        position = end;
+    }
+
+    if count != header.count {
+        return Err(FileError::Corrupted("The blocks do not add up to the header.".to_string()));
+    }
+    Ok(blocks)
+}
+
+pub type Chunk<'a> = Result<Cow<'a, [u64]>, FileError>;
+
+/// Reads the primes file through a map where the format allows it, and through the
+/// buffered pages otherwise.
+pub enum PrimesReader {
+    Mapped(MappedPrimes),
+    Buffered(PrimesPagination),
+}
+
+impl PrimesReader {
+    pub fn header(&self) -> &Header {
+        match *self {
+            PrimesReader::Mapped(ref mapped) => mapped.header(),
+            PrimesReader::Buffered(ref pager) => pager.header(),
+        }
+    }
+
+    /// The primes in order, as views into the map or as buffered pages.
+    pub fn chunks<'a>(&'a mut self) -> Box<dyn Iterator<Item = Chunk<'a>> + 'a> {
+        match *self {
+            PrimesReader::Mapped(ref mapped) => {
+                Box::new(mapped.blocks().map(|block| Ok(Cow::Borrowed(block))))
+            }
+            PrimesReader::Buffered(ref mut pager) => Box::new(pager.map(|page| page.map(Cow::Owned))),
+        }
+    }
+}
+
+/// Opens the primes file for reading, mapped if possible, and with the page size of
+/// the default config if not.
+pub fn read_primes(file_name: String) -> Result<PrimesReader, FileError> {
+    read_primes_paged(file_name, Config::default().page_size())
+}
+
+/// Like `read_primes`, with pages of (at least) `page_size` primes if the file
+/// cannot be mapped.
+pub fn read_primes_paged(file_name: String, page_size: usize) -> Result<PrimesReader, FileError> {
+    let mut file = File::open(&file_name)?;
+    let header = read_header(&mut file)?;
+
+    if MappedPrimes::can_map(&header) {
+        Ok(PrimesReader::Mapped(MappedPrimes::open(file_name)?))
+    } else {
+        Ok(PrimesReader::Buffered(load_primes_paged(file_name, page_size)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_primes, MappedPrimes, PrimesReader};
+    use fs::errors::FileError;
+    use fs::fs::save_primes_as;
+    use fs::format::{Encoding, HEADER_SIZE, BLOCK_HEADER_SIZE};
+    use std::borrow::Cow;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use test_util::{clean_up, temp_file};
+
+    fn read_all(fname: &str) -> (bool, Vec<u64>) {
+        let mut reader = match read_primes(fname.to_string()) {
+            Ok(reader) => reader,
+            Err(err) => panic!("{}", err),
+        };
+        let mut borrowed = true;
+        let mut primes = Vec::new();
+        for chunk in reader.chunks() {
+            match chunk {
+                Ok(Cow::Borrowed(chunk)) => primes.extend_from_slice(chunk),
+                Ok(Cow::Owned(chunk)) => {
+                    borrowed = false;
+                    primes.extend(chunk)
+                }
+                Err(err) => panic!("{}", err),
+            }
+        }
+        (borrowed, primes)
+    }
+
+    #[test]
+    fn maps_raw_files_without_copying() {
+        let fname = temp_file("raw");
+        let numbers: Vec<u64> = (0..150_000).map(|i| 2 * i + 1).collect();
+        assert!(save_primes_as(&numbers[..1_000], fname.clone(), Encoding::Raw).is_ok());
+        assert!(save_primes_as(&numbers, fname.clone(), Encoding::Raw).is_ok());
+
+        match MappedPrimes::open(fname.clone()) {
+            Ok(mapped) => {
+                assert_eq!(mapped.block_count(), 4);
+                assert_eq!(mapped.header().count, 150_000);
+            }
+            Err(err) => panic!("{}", err),
+        }
+        assert_eq!(read_all(&fname), (true, numbers));
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn falls_back_to_pages_for_compressed_files() {
+        let fname = temp_file("compressed");
+        let numbers: Vec<u64> = (0..150_000).map(|i| 2 * i + 1).collect();
+        assert!(save_primes_as(&numbers, fname.clone(), Encoding::DeltaVarint).is_ok());
+
+        match read_primes(fname.clone()) {
+            Ok(PrimesReader::Buffered(_)) => {}
+            _ => panic!("compressed file was not read through pages"),
+        }
+        assert_eq!(read_all(&fname), (false, numbers));
+        clean_up(&fname);
+    }
+
+    #[test]
+    fn detects_corrupted_block_when_mapping() {
+        let fname = temp_file("corrupted");
+        assert!(save_primes_as(&[2, 3, 5, 7], fname.clone(), Encoding::Raw).is_ok());
+        let mut file = OpenOptions::new().write(true).open(&fname).unwrap();
+        file.seek(SeekFrom::Start((HEADER_SIZE + BLOCK_HEADER_SIZE + 8) as u64)).unwrap();
+        file.write_all(&[4]).unwrap();
+
+        match MappedPrimes::open(fname.clone()) {
+            Err(FileError::Corrupted(_)) => {}
+            _ => panic!("corrupted block was not detected"),
+        }
+        clean_up(&fname);
+    }
+}
@@ -1,5 +1,18 @@
+#[allow(clippy::module_inception)]
 mod fs;
-pub use self::fs::load_primes;
-pub use self::fs::save_primes;
+pub use self::fs::{load_primes, load_primes_paged, PrimesPagination};
+pub use self::fs::{save_primes, save_primes_as};
+mod index;
+pub use self::index::{open_index, open_index_read_only, update_index, PrimesIndex};
+mod journal;
+pub use self::journal::{open_journal, remove_journal, Checkpoint, Journal, Round};
+
+mod mapped;
+pub use self::mapped::{read_primes, read_primes_paged, Chunk, MappedPrimes, PrimesReader};
 
 mod serializer;
+mod checksum;
+mod format;
+pub use self::format::{Encoding, Header};
+mod errors;
+pub use self::errors::FileError;
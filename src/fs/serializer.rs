@@ -1,13 +1,17 @@
 use std::vec::Vec;
 
 const SIZE: usize = 8;
+
+/// Longest varint a u64 can take: 7 bits per byte.
+pub const MAX_VARINT_LEN: usize = 64usize.div_ceil(7);
+
 pub fn serialize_u64(num: u64) -> [u8; SIZE] {
     let mut arr = [0u8; SIZE];
 
     let mut num = num;
-    for i in 0..SIZE {
-        arr[i] = (num & 255u64) as u8;
-        num = num >> 8;
+    for byte in arr.iter_mut() {
+        *byte = (num & 255u64) as u8;
+        num >>= 8;
     }
 
     arr
@@ -15,9 +19,9 @@ pub fn serialize_u64(num: u64) -> [u8; SIZE] {
 
 pub fn deserialize_u64(arr: [u8; SIZE]) -> u64 {
     let mut num = 0u64;
-    for i in (0..SIZE).rev() {
-        num = num << 8;
-        num = num + (arr[i] as u64);
+    for &byte in arr.iter().rev() {
+        num <<= 8;
+        num += byte as u64;
     }
     num
 }
@@ -36,3 +40,173 @@ pub fn deserialize_buf(buf: &[u8], read: usize) -> Vec<u64> {
 fn deserialize_chunk(vec: &[u8]) -> u64 {
     deserialize_u64([vec[0], vec[1], vec[2], vec[3], vec[4], vec[5], vec[6], vec[7]])
 }
+
+/// Appends `num` as a LEB128 varint: 7 bits per byte, lowest bits first, with the
+/// high bit set on every byte but the last.
+pub fn serialize_varint(num: u64, out: &mut Vec<u8>) {
+    let mut num = num;
+    while num >= 128 {
+        out.push((num & 127u64) as u8 | 128);
+        num >>= 7;
+    }
+    out.push(num as u8);
+}
+
+/// Reads the varint starting at `*pos` and moves `*pos` past it. None if the buffer
+/// ends first or the value does not fit a u64.
+pub fn deserialize_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut num = 0u64;
+    let mut shift = 0;
+    loop {
+        let &byte = buf.get(*pos)?;
+        *pos += 1;
+
+        let bits = (byte & 127) as u64;
+        if shift == 63 && bits > 1 || shift > 63 {
+            return None;
+        }
+        num |= bits << shift;
+
+        if byte < 128 {
+            return Some(num);
+        }
+        shift += 7;
+    }
+}
+
+/// Delta encoding of ascending primes: the first prime as is, then each gap to the
+/// previous prime as a varint. Gaps after an odd prime are even and stored halved.
+/// None if the numbers are not strictly ascending or have an odd gap after an odd number.
+pub fn serialize_deltas(primes: &[u64]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(primes.len() * 2);
+    let mut prev = None;
+    for &p in primes {
+        match prev {
+            None => serialize_varint(p, &mut out),
+            Some(prev) if p <= prev => return None,
+            Some(prev) if prev % 2 == 1 => {
+                let gap = p - prev;
+                if gap % 2 == 1 {
+                    return None;
+                }
+                serialize_varint(gap / 2, &mut out)
+            }
+            Some(prev) => serialize_varint(p - prev, &mut out),
+        }
+        prev = Some(p);
+    }
+    Some(out)
+}
+
+/// Decodes `count` primes written by `serialize_deltas`. None if the buffer does not
+/// hold exactly that.
+pub fn deserialize_deltas(buf: &[u8], count: usize) -> Option<Vec<u64>> {
+    let mut primes = Vec::with_capacity(count);
+    let mut pos = 0;
+    let mut prev: Option<u64> = None;
+    for _ in 0..count {
+        let value = deserialize_varint(buf, &mut pos)?;
+        let p = match prev {
+            None => value,
+            Some(prev) if prev % 2 == 1 => prev.checked_add(value.checked_mul(2)?)?,
+            Some(prev) => prev.checked_add(value)?,
+        };
+        primes.push(p);
+        prev = Some(p);
+    }
+
+    if pos == buf.len() {
+        Some(primes)
+    } else {
+        None
+    }
+}
+
+pub fn serialize_u32(num: u32) -> [u8; 4] {
+    let mut arr = [0u8; 4];
+
+    let mut num = num;
+    for byte in arr.iter_mut() {
+        *byte = (num & 255u32) as u8;
+        num >>= 8;
+    }
+
+    arr
+}
+
+pub fn deserialize_u32(arr: [u8; 4]) -> u32 {
+    let mut num = 0u32;
+    for &byte in arr.iter().rev() {
+        num <<= 8;
+        num += byte as u32;
+    }
+    num
+}
+
+/// Reads the u64 stored at `at` in `buf`.
+pub fn deserialize_u64_at(buf: &[u8], at: usize) -> u64 {
+    deserialize_chunk(&buf[at..at + SIZE])
+}
+
+/// Reads the u32 stored at `at` in `buf`.
+pub fn deserialize_u32_at(buf: &[u8], at: usize) -> u32 {
+    deserialize_u32([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deserialize_buf, deserialize_deltas, deserialize_varint, serialize_deltas,
+                serialize_u64, serialize_varint, MAX_VARINT_LEN};
+    use test_util::is_prime;
+
+    fn primes_below(limit: u64) -> Vec<u64> {
+        (2..limit).filter(|&n| is_prime(n)).collect()
+    }
+
+    #[test]
+    fn varint_round_trips() {
+        for &num in &[0, 1, 127, 128, 300, 1 << 35, u64::MAX - 1, u64::MAX] {
+            let mut buf = Vec::new();
+            serialize_varint(num, &mut buf);
+            let mut pos = 0;
+            assert_eq!(deserialize_varint(&buf, &mut pos), Some(num));
+            assert_eq!(pos, buf.len());
+            assert!(buf.len() <= MAX_VARINT_LEN);
+        }
+    }
+
+    #[test]
+    fn varint_rejects_overflow_and_truncation() {
+        let mut pos = 0;
+        assert_eq!(deserialize_varint(&[255; 10], &mut pos), None);
+        pos = 0;
+        assert_eq!(deserialize_varint(&[128, 128], &mut pos), None);
+    }
+
+    #[test]
+    fn deltas_match_raw_encoding() {
+        let primes = primes_below(20_000);
+        let raw: Vec<u8> = primes.iter().flat_map(|&p| serialize_u64(p).to_vec()).collect();
+        let deltas = serialize_deltas(&primes).unwrap();
+
+        assert_eq!(deserialize_deltas(&deltas, primes.len()).unwrap(),
+                   deserialize_buf(&raw, raw.len()));
+        assert!(deltas.len() * 7 < raw.len());
+    }
+
+    #[test]
+    fn deltas_of_block_not_starting_at_two() {
+        let primes = vec![18_446_744_073_709_551_427, 18_446_744_073_709_551_437,
+                          18_446_744_073_709_551_521, 18_446_744_073_709_551_557];
+        let deltas = serialize_deltas(&primes).unwrap();
+        assert_eq!(deserialize_deltas(&deltas, primes.len()).unwrap(), primes);
+    }
+
+    #[test]
+    fn deltas_reject_what_they_cannot_encode() {
+        assert_eq!(serialize_deltas(&[7, 5]), None);
+        assert_eq!(serialize_deltas(&[5, 5]), None);
+        assert_eq!(serialize_deltas(&[7, 10]), None);
+        assert_eq!(deserialize_deltas(&[2, 1, 1], 2), None);
+    }
+}
@@ -0,0 +1,9 @@
+extern crate libc;
+extern crate memmap2;
+
+pub mod config;
+pub mod engine;
+pub mod fs;
+pub mod sieve;
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,92 @@
+use prime_sieve::sieve::{Progress, ProgressSink};
+use std::io::{stderr, Write};
+use std::time::{Duration, Instant};
+
+const WIDTH: usize = 30;
+
+/// Least time between two redraws, and before the first.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Draws the progress of each request as a bar on one line of stderr, with the rate
+/// so far and the time it will take at that rate. A request in stages gets a bar,
+/// rate and time for each stage. The line is wiped when the request is over, so
+/// only a terminal should get one.
+pub struct ProgressBar {
+    request: String,
+    stage: Option<String>,
+    started: Instant, // of the request or its stage
+    drawn: Option<Instant>,
+}
+
+impl ProgressBar {
+    pub fn new() -> ProgressBar {
+        ProgressBar {
+            request: String::new(),
+            stage: None,
+            started: Instant::now(),
+            drawn: None,
+        }
+    }
+}
+
+impl ProgressSink for ProgressBar {
+    fn start(&mut self, request: &str, _: u64) {
+        self.request = request.to_string();
+        self.stage = None;
+        self.started = Instant::now();
+        self.drawn = None;
+    }
+
+    fn stage(&mut self, stage: &str, _: u64) {
+        self.stage = Some(stage.to_string());
+        self.started = Instant::now();
+    }
+
+    fn update(&mut self, progress: &Progress) {
+        // Quick requests are over before they are ever drawn.
+        if self.drawn.unwrap_or(self.started).elapsed() < REDRAW_INTERVAL {
+            return;
+        }
+        self.drawn = Some(Instant::now());
+
+        let done = if progress.total == 0 {
+            1.0
+        } else {
+            progress.processed as f64 / progress.total as f64
+        };
+        let filled = (done * WIDTH as f64) as usize;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = progress.processed as f64 / elapsed.max(1e-9);
+        let eta = if progress.processed == 0 {
+            "?".to_string()
+        } else {
+            duration((progress.total - progress.processed) as f64 / rate)
+        };
+
+        let label = match self.stage {
+            Some(ref stage) => format!("{}, {}", self.request, stage),
+            None => self.request.clone(),
+        };
+        let _ = write!(stderr(),
+                       "\r\x1b[2K{} [{}{}] {:5.1}% {} primes, {:.3e} numbers/s, ETA {}",
+                       label,
+                       "#".repeat(filled),
+                       ".".repeat(WIDTH - filled),
+                       done * 100.0,
+                       progress.found,
+                       rate,
+                       eta);
+    }
+
+    fn finish(&mut self) {
+        if self.drawn.is_some() {
+            let _ = write!(stderr(), "\r\x1b[2K");
+        }
+    }
+}
+
+/// Seconds as h:mm:ss.
+fn duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
@@ -0,0 +1,56 @@
+// Pinning of worker threads to CPUs. Only Linux is supported, elsewhere the workers
+// run wherever the scheduler puts them.
+
+/// The CPUs the process may run on, ascending. Empty if they cannot be found out.
+#[cfg(target_os = "linux")]
+pub fn allowed_cpus() -> Vec<usize> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Vec::new();
+        }
+        (0..libc::CPU_SETSIZE as usize).filter(|&cpu| libc::CPU_ISSET(cpu, &set)).collect()
+    }
+}
+
+/// Pins the calling thread to the CPU. Returns whether that worked.
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: usize) -> bool {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return false;
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn allowed_cpus() -> Vec<usize> {
+    Vec::new()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cpu: usize) -> bool {
+    false
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::{allowed_cpus, pin_current_thread};
+    use std::thread;
+
+    #[test]
+    fn pins_a_thread_to_one_allowed_cpu() {
+        let cpus = allowed_cpus();
+        assert!(!cpus.is_empty());
+
+        let last = *cpus.last().unwrap();
+        let pinned = thread::spawn(move || (pin_current_thread(last), allowed_cpus()))
+            .join()
+            .unwrap();
+        assert_eq!(pinned, (true, vec![last]));
+        assert_eq!(allowed_cpus(), cpus);
+    }
+}
@@ -0,0 +1,98 @@
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const NO_DEADLINE: u64 = u64::MAX;
+
+/// Cancels the requests of a thread pool. Clones share the same state, so any of
+/// them can cancel from any thread, a signal handler included. Workers look at the
+/// token between segments and the pool before handing out a work unit.
+///
+/// The pool takes back the cancel and the deadline once a request ends, whether it
+/// was cancelled or not. A cancel that comes between two requests cancels the next
+/// one.
+#[derive(Clone)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    cancelled: AtomicBool,
+    created: Instant,
+    deadline: AtomicU64, // nanoseconds after `created`
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken {
+            inner: Arc::new(Inner {
+                cancelled: AtomicBool::new(false),
+                created: Instant::now(),
+                deadline: AtomicU64::new(NO_DEADLINE),
+            }),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Cancels whatever runs once the timeout is over.
+    pub fn cancel_after(&self, timeout: Duration) {
+        let deadline = self.inner.created.elapsed() + timeout;
+        let nanos = u64::try_from(deadline.as_nanos()).unwrap_or(NO_DEADLINE - 1);
+        self.inner.deadline.store(nanos, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        if self.inner.cancelled.load(Ordering::SeqCst) {
+            return true;
+        }
+        match self.inner.deadline.load(Ordering::SeqCst) {
+            NO_DEADLINE => false,
+            nanos => self.inner.created.elapsed() >= Duration::from_nanos(nanos),
+        }
+    }
+
+    /// Takes back the cancel and the deadline.
+    pub fn reset(&self) {
+        self.inner.deadline.store(NO_DEADLINE, Ordering::SeqCst);
+        self.inner.cancelled.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> CancelToken {
+        CancelToken::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CancelToken;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn clones_share_the_cancel() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert!(!token.is_cancelled());
+        thread::spawn(move || clone.cancel()).join().unwrap();
+        assert!(token.is_cancelled());
+        token.reset();
+        assert!(!token.is_cancelled());
+    }
+
+    #[test]
+    fn deadline_passes() {
+        let token = CancelToken::new();
+        token.cancel_after(Duration::from_secs(3600));
+        assert!(!token.is_cancelled());
+        token.cancel_after(Duration::from_millis(0));
+        assert!(token.is_cancelled());
+        token.reset();
+        assert!(!token.is_cancelled());
+    }
+}
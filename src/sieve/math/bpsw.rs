@@ -0,0 +1,219 @@
+// Baillie–PSW: a strong probable prime test to base 2 followed by a strong Lucas
+// probable prime test with Selfridge's parameters. No composite is known to pass
+// both, and none exists below 2^64.
+
+use sieve::math::montgomery::Montgomery;
+use sieve::math::primality::is_prime;
+
+/// Whether n is prime. Exact below 2^64, and without known counterexample above.
+/// `small_primes` are ascending primes to try dividing by first; if they reach √n
+/// trial division already decides.
+pub fn is_prime_u128(n: u128, small_primes: &[u64]) -> bool {
+    for &p in small_primes {
+        let p = p as u128;
+        if p * p > n {
+            return n > 1;
+        }
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+
+    if n <= u64::MAX as u128 {
+        return is_prime(n as u64);
+    }
+    bpsw(n)
+}
+
+/// The Baillie–PSW test on its own. n must be odd and above 2^64.
+fn bpsw(n: u128) -> bool {
+    if n & 1 == 0 {
+        return false;
+    }
+    let m = Montgomery::new(n);
+    strong_probable_prime(&m, 2) && strong_lucas_probable_prime(&m)
+}
+
+fn strong_probable_prime(m: &Montgomery, base: u128) -> bool {
+    let n = m.modulus();
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+    let one = m.one();
+    let minus_one = m.sub(0, one);
+
+    let mut x = m.pow(m.to_mont(base), d);
+    if x == one || x == minus_one {
+        return true;
+    }
+    for _ in 1..s {
+        x = m.mul(x, x);
+        if x == minus_one {
+            return true;
+        }
+    }
+    false
+}
+
+fn strong_lucas_probable_prime(m: &Montgomery) -> bool {
+    let n = m.modulus();
+    if is_square(n) {
+        return false;
+    }
+
+    // Selfridge: the first D of 5, -7, 9, -11, ... with (D / n) = -1, P = 1 and
+    // Q = (1 - D) / 4.
+    let mut d: i128 = 5;
+    loop {
+        match jacobi(signed_mod(d, n), n) {
+            -1 => break,
+            0 if d.unsigned_abs() != n => return false,
+            _ => {}
+        }
+        d = if d > 0 { -(d + 2) } else { -d + 2 };
+    }
+    let q = (1 - d) / 4;
+
+    let d_m = m.to_mont(signed_mod(d, n));
+    let q_m = m.to_mont(signed_mod(q, n));
+
+    // n + 1 = k 2^s with k odd. Only 2^128 - 1 has no n + 1, and 3 divides it.
+    let n_plus_one = match n.checked_add(1) {
+        Some(n_plus_one) => n_plus_one,
+        None => return false,
+    };
+    let s = n_plus_one.trailing_zeros();
+    let k = n_plus_one >> s;
+
+    // U_k, V_k and Q^k by binary exponentiation from the top bit, with P = 1.
+    let (mut u, mut v, mut qk) = (m.one(), m.one(), q_m);
+    for bit in (0..127 - k.leading_zeros()).rev() {
+        // Double: U_2j = U_j V_j, V_2j = V_j^2 - 2 Q^j
+        u = m.mul(u, v);
+        v = m.sub(m.mul(v, v), m.add(qk, qk));
+        qk = m.mul(qk, qk);
+        if (k >> bit) & 1 == 1 {
+            // Step: U_j+1 = (U_j + V_j) / 2, V_j+1 = (D U_j + V_j) / 2
+            let next_u = m.half(m.add(u, v));
+            v = m.half(m.add(m.mul(d_m, u), v));
+            u = next_u;
+            qk = m.mul(qk, q_m);
+        }
+    }
+
+    if u == 0 || v == 0 {
+        return true;
+    }
+    for _ in 1..s {
+        // V_2j = V_j^2 - 2 Q^j
+        v = m.sub(m.mul(v, v), m.add(qk, qk));
+        qk = m.mul(qk, qk);
+        if v == 0 {
+            return true;
+        }
+    }
+    false
+}
+
+fn signed_mod(a: i128, n: u128) -> u128 {
+    if a >= 0 {
+        a as u128 % n
+    } else {
+        (n - a.unsigned_abs() % n) % n
+    }
+}
+
+/// The Jacobi symbol (a / n) for odd n.
+fn jacobi(mut a: u128, mut n: u128) -> i32 {
+    let mut result = 1;
+    a %= n;
+    while a != 0 {
+        while a & 1 == 0 {
+            a >>= 1;
+            if n % 8 == 3 || n % 8 == 5 {
+                result = -result;
+            }
+        }
+        std::mem::swap(&mut a, &mut n);
+        if a % 4 == 3 && n % 4 == 3 {
+            result = -result;
+        }
+        a %= n;
+    }
+    if n == 1 { result } else { 0 }
+}
+
+fn is_square(n: u128) -> bool {
+    let mut r = (n as f64).sqrt() as u128;
+    while r.checked_mul(r).is_none_or(|square| square > n) {
+        r -= 1;
+    }
+    while (r + 1).checked_mul(r + 1).is_some_and(|square| square <= n) {
+        r += 1;
+    }
+    r * r == n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bpsw, is_prime_u128, jacobi, strong_lucas_probable_prime};
+    use sieve::math::montgomery::Montgomery;
+    use sieve::math::primality::is_prime;
+
+    #[test]
+    fn agrees_with_miller_rabin_on_odd_numbers() {
+        // bpsw itself is meant for big numbers, but must be right for small ones too.
+        for n in (5..20_000u128).step_by(2) {
+            assert_eq!(bpsw(n), is_prime(n as u64), "{}", n);
+        }
+    }
+
+    #[test]
+    fn lucas_rejects_lucas_pseudoprimes_only_with_base_2() {
+        // Strong Lucas pseudoprimes, which the base 2 test catches.
+        for &n in &[5_459u128, 5_777, 10_877, 16_109, 18_971] {
+            let m = Montgomery::new(n);
+            assert!(strong_lucas_probable_prime(&m), "{}", n);
+            assert!(!bpsw(n), "{}", n);
+        }
+    }
+
+    #[test]
+    fn lucas_survives_the_largest_odd_number() {
+        assert!(!strong_lucas_probable_prime(&Montgomery::new(u128::MAX)));
+        assert!(!bpsw(u128::MAX));
+        assert!(!is_prime_u128(u128::MAX, &[]));
+    }
+
+    #[test]
+    fn decides_128_bit_numbers() {
+        let primes = [u128::MAX - 158, (1 << 127) - 1, 18_446_744_073_709_551_629,
+                      170_141_183_460_469_231_731_687_303_715_884_105_757];
+        for &n in &primes {
+            assert!(is_prime_u128(n, &[2, 3, 5, 7, 11, 13]), "{}", n);
+        }
+
+        let p = 18_446_744_073_709_551_557u128; // largest prime below 2^64
+        let composites = [u128::MAX, (1 << 127) + 1, p * p, p * 18_446_744_073_709_551_533,
+                          (1 << 89) - 1 - 2, 3_825_123_056_546_413_051 * 5];
+        for &n in &composites {
+            assert!(!is_prime_u128(n, &[2, 3]), "{}", n);
+        }
+    }
+
+    #[test]
+    fn trial_division_decides_small_inputs() {
+        let primes = [2, 3, 5, 7, 11, 13];
+        let decided: Vec<u128> = (0..170).filter(|&n| is_prime_u128(n, &primes)).collect();
+        let expected: Vec<u128> = (0..170).filter(|&n| is_prime(n as u64)).collect();
+        assert_eq!(decided, expected);
+    }
+
+    #[test]
+    fn jacobi_symbol() {
+        assert_eq!(jacobi(5, 21), 1);
+        assert_eq!(jacobi(2, 15), 1);
+        assert_eq!(jacobi(1001, 9907), -1);
+        assert_eq!(jacobi(19, 45), 1);
+        assert_eq!(jacobi(6, 9), 0);
+    }
+}
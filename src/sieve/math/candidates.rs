@@ -0,0 +1,220 @@
+use std::iter::{Cloned, Peekable};
+use std::slice::Iter as SliceIter;
+use std::sync::Arc;
+use std::vec::Vec;
+use sieve::math::errors::MathError;
+use sieve::math::wheel::Wheel;
+
+const WORD: u64 = 64;
+
+/// Set of candidate primes in `[from, to)` stored as one bit per wheel spoke. The few
+/// members that are not spokes (the wheel primes themselves) are kept in a short
+/// sorted list next to the bits. Numbers only come out as u64 through `iter`.
+#[derive(Clone)]
+pub struct CandidateSet {
+    wheel: Arc<Wheel>,
+    from: u64,
+    to: u64,
+    start: u64, // wheel index of bit 0
+    end: u64, // wheel index after the last bit
+    bits: Vec<u64>,
+    off_wheel: Vec<u64>,
+}
+
+impl CandidateSet {
+    /// Empty set able to hold the numbers in `[from, to)`.
+    pub fn new(wheel: Arc<Wheel>, from: u64, to: u64) -> CandidateSet {
+        let start = wheel.index_of(from);
+        let end = wheel.index_of(to).max(start);
+        CandidateSet {
+            wheel,
+            from,
+            to,
+            start,
+            end,
+            bits: vec![0; (end - start).div_ceil(WORD) as usize],
+            off_wheel: Vec::new(),
+        }
+    }
+
+    /// Set of the given numbers, which must be strictly ascending.
+    pub fn from_numbers(wheel: Arc<Wheel>, numbers: &[u64]) -> Result<CandidateSet, MathError> {
+        if numbers.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(MathError::Unsorted("Candidates must be strictly ascending.".to_string()));
+        }
+
+        let (from, to) = match (numbers.first(), numbers.last()) {
+            (Some(&first), Some(&last)) => (first, last.saturating_add(1)),
+            _ => (0, 0),
+        };
+
+        let mut set = CandidateSet::new(wheel, from, to);
+        for &n in numbers {
+            set.insert(n);
+        }
+        Ok(set)
+    }
+
+    pub fn wheel(&self) -> &Arc<Wheel> {
+        &self.wheel
+    }
+
+    pub fn from(&self) -> u64 {
+        self.from
+    }
+
+    pub fn to(&self) -> u64 {
+        self.to
+    }
+
+    /// `n` must lie in `[from, to)`.
+    pub fn insert(&mut self, n: u64) {
+        if self.wheel.is_spoke(n) {
+            let index = self.wheel.index_of(n);
+            self.insert_index(index);
+        } else if let Err(position) = self.off_wheel.binary_search(&n) {
+            self.off_wheel.insert(position, n);
+        }
+    }
+
+    /// Sets the bit of the spoke with the given wheel index.
+    pub fn insert_index(&mut self, index: u64) {
+        let (word, bit) = self.locate(index);
+        self.bits[word] |= 1 << bit;
+    }
+
+    /// Clears the bit of the spoke with the given wheel index. Returns whether it was
+    /// set.
+    pub fn remove_index(&mut self, index: u64) -> bool {
+        let (word, bit) = self.locate(index);
+        let was_set = self.bits[word] & (1 << bit) != 0;
+        self.bits[word] &= !(1 << bit);
+        was_set
+    }
+
+    /// Wheel index of the first member spoke at or after `index`.
+    pub fn next_index(&self, index: u64) -> Option<u64> {
+        let index = index.max(self.start);
+        if index >= self.end {
+            return None;
+        }
+
+        let (mut word, bit) = self.locate(index);
+        let mut bits = self.bits[word] & (u64::MAX << bit);
+        loop {
+            if bits != 0 {
+                return Some(self.start + word as u64 * WORD + bits.trailing_zeros() as u64);
+            }
+            word += 1;
+            if word == self.bits.len() {
+                return None;
+            }
+            bits = self.bits[word];
+        }
+    }
+
+    /// Keeps only the members off the wheel for which `keep` holds.
+    pub fn retain_off_wheel<F>(&mut self, keep: F)
+        where F: FnMut(&u64) -> bool
+    {
+        self.off_wheel.retain(keep);
+    }
+
+    pub fn len(&self) -> usize {
+        let spokes: u32 = self.bits.iter().map(|word| word.count_ones()).sum();
+        spokes as usize + self.off_wheel.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The members in ascending order.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            set: self,
+            next_index: self.start,
+            off_wheel: self.off_wheel.iter().cloned().peekable(),
+        }
+    }
+
+    fn locate(&self, index: u64) -> (usize, u64) {
+        let offset = index - self.start;
+        ((offset / WORD) as usize, offset % WORD)
+    }
+}
+
+pub struct Iter<'a> {
+    set: &'a CandidateSet,
+    next_index: u64,
+    off_wheel: Peekable<Cloned<SliceIter<'a, u64>>>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let spoke = self.set
+            .next_index(self.next_index)
+            .map(|index| (index, self.set.wheel.number_at(index)));
+
+        match (spoke, self.off_wheel.peek()) {
+            (Some((_, n)), Some(&m)) if m < n => self.off_wheel.next(),
+            (Some((index, n)), _) => {
+                self.next_index = index + 1;
+                Some(n)
+            }
+            (None, _) => self.off_wheel.next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CandidateSet;
+    use std::sync::Arc;
+    use sieve::math::wheel::Wheel;
+
+    #[test]
+    fn round_trips_numbers() {
+        let numbers: Vec<u64> = vec![2, 3, 5, 7, 9, 11, 49, 64, 97, 1000, 1001, 99_991];
+        let set = CandidateSet::from_numbers(Arc::new(Wheel::new(3)), &numbers).unwrap();
+
+        assert_eq!(set.iter().collect::<Vec<u64>>(), numbers);
+        assert_eq!(set.len(), numbers.len());
+    }
+
+    #[test]
+    fn rejects_unsorted_numbers() {
+        assert!(CandidateSet::from_numbers(Arc::new(Wheel::new(3)), &[7, 5]).is_err());
+    }
+
+    #[test]
+    fn next_index_crosses_words() {
+        let wheel = Arc::new(Wheel::new(3));
+        let mut set = CandidateSet::new(wheel.clone(), 1000, 100_000);
+        let far = wheel.index_of(90_001);
+        set.insert_index(far);
+
+        assert_eq!(set.next_index(0), Some(far));
+        assert_eq!(set.next_index(far + 1), None);
+
+        set.remove_index(far);
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn empty_range() {
+        let set = CandidateSet::from_numbers(Arc::new(Wheel::new(5)), &[]).unwrap();
+        assert!(set.is_empty());
+        assert_eq!(set.iter().next(), None);
+    }
+
+    #[test]
+    fn range_without_spokes() {
+        // Nothing in [8, 11) is coprime to 30, so the set has no bits at all.
+        let set = CandidateSet::new(Arc::new(Wheel::new(3)), 8, 11);
+        assert_eq!(set.next_index(0), None);
+        assert_eq!(set.iter().next(), None);
+    }
+}
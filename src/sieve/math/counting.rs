@@ -0,0 +1,118 @@
+// Prime counting with Lucy_Hedgehog's algorithm: for every v = x / i it keeps the
+// number S(v) of integers in [2, v] that survive sieving by the primes handled so
+// far. Sieving by a prime p changes S(v) by the survivors in [2, v / p] that are not
+// below p, so each v depends only on smaller values. Needs O(√x) memory and
+// O(x^(3/4)) time.
+
+use std::mem::size_of;
+
+/// Number of primes up to and including `x`. `small_primes` must be ascending
+/// primes starting at 2; they are used while they reach, and the rest of the primes
+/// up to √x are recognised from the counts themselves.
+pub fn prime_count(x: u64, small_primes: &[u64]) -> u64 {
+    if x < 2 {
+        return 0;
+    }
+
+    let root = isqrt(x);
+    let r = root as usize;
+    // small[v] = S(v) for v <= √x, large[i] = S(x / i) for i <= √x.
+    let mut small: Vec<u64> = (0..r as u64 + 1).map(|v| v.saturating_sub(1)).collect();
+    let mut large: Vec<u64> = (0..r as u64 + 1).map(|i| x.checked_div(i).map_or(0, |v| v - 1)).collect();
+
+    let table = small_primes.iter().cloned().take_while(|&p| p <= root);
+    let beyond_table = small_primes.last().map_or(2, |&p| p + 1).max(2);
+    let rest = beyond_table..root + 1;
+
+    for p in table.chain(rest) {
+        let below = small[p as usize - 1];
+        if small[p as usize] == below {
+            // Sieved out, so not a prime.
+            continue;
+        }
+
+        let square = p * p;
+        let last_i = (x / square).min(root) as usize;
+        for i in 1..last_i + 1 {
+            let d = i as u64 * p;
+            let s = if d <= root {
+                large[d as usize]
+            } else {
+                small[(x / d) as usize]
+            };
+            large[i] -= s - below;
+        }
+        for v in (square as usize..r + 1).rev() {
+            small[v] -= small[v / p as usize] - below;
+        }
+    }
+
+    large[1]
+}
+
+/// Bytes of the tables that `prime_count(x, _)` allocates.
+pub fn prime_count_memory(x: u64) -> u64 {
+    2 * (isqrt(x) + 1) * size_of::<u64>() as u64
+}
+
+/// Largest r with r² <= n.
+fn isqrt(n: u64) -> u64 {
+    let mut r = (n as f64).sqrt() as u64;
+    while r.checked_mul(r).is_none_or(|square| square > n) {
+        r -= 1;
+    }
+    while (r + 1).checked_mul(r + 1).is_some_and(|square| square <= n) {
+        r += 1;
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{isqrt, prime_count, prime_count_memory};
+    use test_util::is_prime;
+
+    #[test]
+    fn counts_like_trial_division() {
+        let mut count = 0;
+        for x in 0..3000 {
+            if is_prime(x) {
+                count += 1;
+            }
+            assert_eq!(prime_count(x, &[]), count, "pi({})", x);
+        }
+    }
+
+    #[test]
+    fn counts_known_values() {
+        assert_eq!(prime_count(1_000_000, &[]), 78_498);
+        assert_eq!(prime_count(1_000_000_000, &[]), 50_847_534);
+        assert_eq!(prime_count(10_000_000_000, &[]), 455_052_511);
+    }
+
+    #[test]
+    fn uses_small_primes_however_far_they_reach() {
+        let primes: Vec<u64> = (0..2000).filter(|&n| is_prime(n)).collect();
+        for &x in &[10, 1_000_000, 3_999_999, 123_456_789] {
+            let expected = prime_count(x, &[]);
+            assert_eq!(prime_count(x, &primes), expected);
+            assert_eq!(prime_count(x, &primes[..10]), expected);
+        }
+    }
+
+    #[test]
+    fn isqrt_is_exact_near_squares() {
+        assert_eq!(isqrt(0), 0);
+        assert_eq!(isqrt(99), 9);
+        assert_eq!(isqrt(100), 10);
+        assert_eq!(isqrt(u64::MAX), u32::MAX as u64);
+        assert_eq!(isqrt((1 << 52) * (1 << 10) - 1), (1 << 31) - 1);
+    }
+
+    #[test]
+    fn memory_covers_both_tables() {
+        assert_eq!(prime_count_memory(99), 2 * 10 * 8);
+        assert_eq!(prime_count_memory(100), 2 * 11 * 8);
+        assert_eq!(prime_count_memory(u64::MAX), 1 << 36);
+    }
+}
@@ -3,12 +3,17 @@ use std::result::Result;
 
 pub enum MathError {
     Limit(String),
+    Unsorted(String),
+    /// The caller asked to stop before the work was done.
+    Cancelled,
 }
 
 impl Debug for MathError {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
-        match self {
-            &MathError::Limit(ref msg) => write!(f, "MathError::Limit({})", msg),
+        match *self {
+            MathError::Limit(ref msg) => write!(f, "MathError::Limit({})", msg),
+            MathError::Unsorted(ref msg) => write!(f, "MathError::Unsorted({})", msg),
+            MathError::Cancelled => write!(f, "MathError::Cancelled"),
         }
     }
 }
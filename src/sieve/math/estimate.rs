@@ -0,0 +1,129 @@
+// Smooth approximations of the prime counting function, for guessing where the n-th
+// prime is before counting exactly.
+
+// Euler–Mascheroni constant.
+const GAMMA: f64 = 0.577_215_664_901_532_9;
+
+/// Logarithmic integral li(x), by Ramanujan's series. Only for x > 1.
+pub fn li(x: f64) -> f64 {
+    let ln_x = x.ln();
+    let mut sum = 0.0;
+    let mut term = 1.0; // (ln x)^n / (n! 2^(n-1)), signed
+    let mut inner = 0.0; // sum of 1 / (2k + 1) for k <= (n - 1) / 2
+    for n in 1..200 {
+        term *= ln_x / n as f64;
+        if n > 1 {
+            term *= -0.5;
+        }
+        if n % 2 == 1 {
+            inner += 1.0 / n as f64;
+        }
+        let next = sum + term * inner;
+        if next == sum {
+            break;
+        }
+        sum = next;
+    }
+    GAMMA + ln_x.ln() + x.sqrt() * sum
+}
+
+/// Riemann's R(x) = sum of μ(k) / k li(x^(1/k)), which is within a few √x / ln x of
+/// π(x) for every x we can count to.
+pub fn riemann_r(x: f64) -> f64 {
+    if x < 2.0 {
+        return 0.0;
+    }
+
+    let mut r = 0.0;
+    for k in 1..64 {
+        let root = x.powf(1.0 / k as f64);
+        if root < 2.0 {
+            break;
+        }
+        let mu = moebius(k);
+        if mu != 0 {
+            r += mu as f64 / k as f64 * li(root);
+        }
+    }
+    r
+}
+
+/// Where R(x) = n, as a guess of the n-th prime. Solved by Newton's method, using
+/// that R'(x) is close to 1 / ln x.
+pub fn nth_prime_estimate(n: u64) -> u64 {
+    if n < 6 {
+        return [0, 2, 3, 5, 7, 11][n as usize];
+    }
+
+    let n = n as f64;
+    let mut x = n * (n.ln() + n.ln().ln() - 1.0);
+    for _ in 0..100 {
+        let step = (riemann_r(x) - n) * x.ln();
+        x -= step;
+        if step.abs() < 0.5 {
+            break;
+        }
+    }
+    x.round() as u64
+}
+
+fn moebius(mut k: u64) -> i32 {
+    let mut mu = 1;
+    let mut d = 2;
+    while d * d <= k {
+        if k.is_multiple_of(d) {
+            k /= d;
+            if k.is_multiple_of(d) {
+                return 0;
+            }
+            mu = -mu;
+        }
+        d += 1;
+    }
+    if k > 1 {
+        mu = -mu;
+    }
+    mu
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{li, moebius, nth_prime_estimate, riemann_r};
+
+    fn assert_close(value: f64, expected: f64, tolerance: f64) {
+        assert!((value - expected).abs() <= tolerance,
+                "{} is not within {} of {}",
+                value,
+                tolerance,
+                expected);
+    }
+
+    #[test]
+    fn li_matches_known_values() {
+        assert_close(li(2.0), 1.045_163_780_117_5, 1e-9);
+        assert_close(li(1e6), 78_627.549_159_5, 1e-3);
+        assert_close(li(1e18), 24_739_954_309_690_415.0, 1e4);
+    }
+
+    #[test]
+    fn riemann_r_is_close_to_pi() {
+        assert_close(riemann_r(1e6), 78_498.0, 30.0);
+        assert_close(riemann_r(1e9), 50_847_534.0, 100.0);
+        assert_close(riemann_r(1e18), 24_739_954_284_239_494.0, 1e3);
+    }
+
+    #[test]
+    fn estimates_nth_prime() {
+        assert_eq!(nth_prime_estimate(5), 11);
+        let estimate = nth_prime_estimate(1_000_000) as f64;
+        assert_close(estimate, 15_485_863.0, 5_000.0);
+        let estimate = nth_prime_estimate(1_000_000_000_000) as f64;
+        assert_close(estimate, 29_996_224_275_833.0, 2e6);
+    }
+
+    #[test]
+    fn moebius_of_small_numbers() {
+        let mu: Vec<i32> = (1..11).map(moebius).collect();
+        assert_eq!(mu, vec![1, -1, -1, 0, -1, 1, -1, 0, 0, 1]);
+    }
+}
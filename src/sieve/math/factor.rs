@@ -0,0 +1,187 @@
+// Factoring u64: trial division by a table of small primes, then Pollard's rho in
+// Brent's variant for whatever is left, with Miller–Rabin to know when to stop.
+
+use sieve::math::primality::{is_prime, mul_mod};
+
+/// The prime factors of n with their exponents, ascending. Empty for 0 and 1.
+/// `small_primes` are ascending primes to divide by first.
+pub fn factor(n: u64, small_primes: &[u64]) -> Vec<(u64, u32)> {
+    let mut factors = Vec::new();
+    let mut rest = n;
+    trial_divide(&mut rest, small_primes, &mut factors);
+    factor_rest(rest, &mut factors);
+    factors
+}
+
+/// Divides every prime of `primes` out of `rest` and adds it to `factors`. The
+/// primes must be ascending and follow the ones tried before. Stops once a prime's
+/// square exceeds `rest`, since `rest` is then prime itself, and leaves 1 behind.
+pub fn trial_divide(rest: &mut u64, primes: &[u64], factors: &mut Vec<(u64, u32)>) {
+    for &p in primes {
+        if *rest <= 1 {
+            return;
+        }
+        if p as u128 * p as u128 > *rest as u128 {
+            factors.push((*rest, 1));
+            *rest = 1;
+            return;
+        }
+        let mut exponent = 0;
+        while rest.is_multiple_of(p) {
+            *rest /= p;
+            exponent += 1;
+        }
+        if exponent > 0 {
+            factors.push((p, exponent));
+        }
+    }
+}
+
+/// Factors what trial division left of n into `factors`, which ends up sorted.
+/// `rest` must have no prime factor below the ones already in `factors`.
+pub fn factor_rest(rest: u64, factors: &mut Vec<(u64, u32)>) {
+    let found = factors.len();
+    let mut stack = vec![rest];
+    while let Some(m) = stack.pop() {
+        if m <= 1 {
+            continue;
+        }
+        if is_prime(m) {
+            factors.push((m, 1));
+        } else {
+            let d = find_divisor(m);
+            stack.push(d);
+            stack.push(m / d);
+        }
+    }
+
+    // Rho finds factors in no particular order and repeats those of prime powers.
+    factors[found..].sort_unstable();
+    let mut merged: Vec<(u64, u32)> = Vec::with_capacity(factors.len());
+    for &(p, e) in factors.iter() {
+        match merged.last_mut() {
+            Some(&mut (q, ref mut f)) if q == p => *f += e,
+            _ => merged.push((p, e)),
+        }
+    }
+    *factors = merged;
+}
+
+/// A proper divisor of the composite n.
+fn find_divisor(n: u64) -> u64 {
+    if n.is_multiple_of(2) {
+        return 2;
+    }
+    (1..).map(|c| brent(n, c)).find(|&d| d != n).unwrap_or(n)
+}
+
+/// Pollard's rho with Brent's cycle detection on x^2 + c. Products of the
+/// differences are taken m at a time so that only every m-th step pays for a gcd.
+/// Returns n when the walk fails, then another c has to be tried.
+fn brent(n: u64, c: u64) -> u64 {
+    let m = 128;
+    let f = |x: u64| ((mul_mod(x, x, n) as u128 + c as u128) % n as u128) as u64;
+
+    let (mut x, mut y, mut ys) = (0, 2, 2);
+    let (mut g, mut q, mut r) = (1, 1, 1u64);
+    while g == 1 {
+        x = y;
+        for _ in 0..r {
+            y = f(y);
+        }
+        let mut k = 0;
+        while k < r && g == 1 {
+            ys = y;
+            for _ in 0..m.min(r - k) {
+                y = f(y);
+                q = mul_mod(q, x.abs_diff(y), n);
+            }
+            g = gcd(q, n);
+            k += m;
+        }
+        r *= 2;
+    }
+
+    if g == n {
+        // The batch overshot, step through it one at a time.
+        loop {
+            ys = f(ys);
+            g = gcd(x.abs_diff(ys), n);
+            if g > 1 {
+                break;
+            }
+        }
+    }
+    g
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{brent, factor, factor_rest, trial_divide};
+
+    fn product(factors: &[(u64, u32)]) -> u64 {
+        factors.iter().map(|&(p, e)| p.pow(e)).product()
+    }
+
+    #[test]
+    fn factors_small_numbers() {
+        assert_eq!(factor(0, &[2, 3]), vec![]);
+        assert_eq!(factor(1, &[2, 3]), vec![]);
+        assert_eq!(factor(360, &[2, 3]), vec![(2, 3), (3, 2), (5, 1)]);
+        for n in 2..20_000 {
+            let factors = factor(n, &[2, 3, 5]);
+            assert_eq!(product(&factors), n, "{}", n);
+            assert!(factors.windows(2).all(|w| w[0].0 < w[1].0), "{}", n);
+        }
+    }
+
+    #[test]
+    fn factors_without_a_table() {
+        let cases: [(u64, Vec<(u64, u32)>); 5] =
+            [(18_446_744_073_709_551_615, vec![(3, 1), (5, 1), (17, 1), (257, 1),
+                                                (641, 1), (65_537, 1), (6_700_417, 1)]),
+             (4_294_967_291 * 4_294_967_279, vec![(4_294_967_279, 1), (4_294_967_291, 1)]),
+             (1 << 63, vec![(2, 63)]),
+             (65_521 * 65_521 * 65_521 * 3, vec![(3, 1), (65_521, 3)]),
+             (18_446_744_073_709_551_557, vec![(18_446_744_073_709_551_557, 1)])];
+        for &(n, ref expected) in cases.iter() {
+            assert_eq!(&factor(n, &[]), expected, "{}", n);
+        }
+    }
+
+    #[test]
+    fn trial_division_in_pieces() {
+        let mut factors = Vec::new();
+        let mut rest = 2 * 2 * 7 * 13 * 1_000_003;
+        trial_divide(&mut rest, &[2, 3, 5], &mut factors);
+        trial_divide(&mut rest, &[7, 11, 13], &mut factors);
+        assert_eq!(rest, 1_000_003);
+        factor_rest(rest, &mut factors);
+        assert_eq!(factors, vec![(2, 2), (7, 1), (13, 1), (1_000_003, 1)]);
+
+        // Trial division alone is enough when the primes reach the square root.
+        let mut factors = Vec::new();
+        let mut rest = 3 * 37;
+        trial_divide(&mut rest, &[2, 3, 5, 7, 11], &mut factors);
+        assert_eq!((rest, factors), (1, vec![(3, 1), (37, 1)]));
+    }
+
+    #[test]
+    fn rho_steps_do_not_overflow() {
+        // x^2 + c passes u64 for a big c once x^2 mod n is close to n.
+        let n = 4_294_967_291 * 4_294_967_279;
+        for &c in &[n - 1, u64::MAX] {
+            let d = brent(n, c);
+            assert!(d > 1 && n.is_multiple_of(d), "{}", c);
+        }
+    }
+}
@@ -1,81 +1,143 @@
 use std::result::Result;
-use std::u32::MAX as u32MAX;
 use std::option::Option;
 use sieve::math::errors::MathError;
 use sieve::math::Partition;
+use sieve::math::segmented::SegmentedSieve;
+use sieve::math::wheel::Wheel;
+use sieve::math::candidates::CandidateSet;
+use std::sync::Arc;
 
 pub fn init_primes() -> Vec<u64> {
     vec![2, 3, 5, 7, 11]
 }
 
-fn is_coprime_to_list(n: u64, list: &Vec<u64>) -> Result<bool, MathError> {
-    if n > (u32MAX as u64) {
-        let msg = format!("Number '{}' is to big for checking co-primality in an \
-                          effective way.",
-                          n);
-        return Err(MathError::Limit(msg.to_string()));
-    };
-    let upper_limit = (n as f64).sqrt() as u64 + 1;
+/// Returns the primes in the partition. `init_primes` must be ascending and contain
+/// every prime up to the square root of the end of the partition.
+pub fn find_candidates(wheel: &Arc<Wheel>,
+                       init_primes: &[u64],
+                       part: Partition)
+                       -> Result<CandidateSet, MathError> {
+    find_candidates_with(wheel, init_primes, part, |_, _| true)
+}
 
-    for p in list {
-        if *p > upper_limit {
-            return Ok(true);
-        } else if !is_coprime(n, *p) {
-            return Ok(false);
-        };
+/// Like `find_candidates`, and calls `on_segment` with the number sieved up to and
+/// the candidates found so far after every segment. Gives up with
+/// `MathError::Cancelled` when it returns false.
+pub fn find_candidates_with<F>(wheel: &Arc<Wheel>,
+                               init_primes: &[u64],
+                               part: Partition,
+                               mut on_segment: F)
+                               -> Result<CandidateSet, MathError>
+    where F: FnMut(u64, u64) -> bool
+{
+    let from = part.from.max(2);
+    let to = part.from + part.delta;
+
+    let reach = init_primes.last().map_or(0, |&p| (p as u128 + 1) * (p as u128 + 1));
+    if reach < to as u128 {
+        let msg = format!("The initial primes only reach {}, which is too little to sieve up \
+                           to {}.",
+                          init_primes.last().cloned().unwrap_or(0),
+                          to);
+        return Err(MathError::Limit(msg));
+    }
+
+    let mut candidates = CandidateSet::new(wheel.clone(), from, to);
+    let mut found = 0;
+    for &p in wheel.primes().iter().filter(|&&p| from <= p && p < to) {
+        candidates.insert(p);
+        found += 1;
+    }
+    for segment in SegmentedSieve::new(wheel, init_primes, from, to) {
+        for index in segment.survivors() {
+            candidates.insert_index(index);
+            found += 1;
+        }
+        if !on_segment(wheel.number_at(segment.end()).min(to), found) {
+            return Err(MathError::Cancelled);
+        }
     }
-    return Ok(true);
+
+    Ok(candidates)
 }
 
-fn is_coprime(n: u64, c: u64) -> bool {
-    !(n % c == 0)
+/// Removes every candidate that is a multiple of a prime in the page (other than the
+/// prime itself). `primes_page` must be ascending. Stretches without candidates are
+/// skipped instead of sieved, and the few candidates off the wheel are checked by
+/// trial division.
+pub fn sieve_page(primes_page: &[u64],
+                  candidates: CandidateSet)
+                  -> Result<CandidateSet, MathError> {
+    sieve_page_with(primes_page, candidates, |_, _| true)
 }
 
-pub fn find_candidates(init_primes: &Vec<u64>, part: Partition) -> Result<Vec<u64>, MathError> {
-    let mut candidates = Vec::new();
-    let to = part.from + part.delta;
+/// Like `sieve_page`, and calls `on_segment` with the number sieved up to and the
+/// candidates left after every segment. Gives up with `MathError::Cancelled` when it
+/// returns false.
+pub fn sieve_page_with<F>(primes_page: &[u64],
+                          mut candidates: CandidateSet,
+                          mut on_segment: F)
+                          -> Result<CandidateSet, MathError>
+    where F: FnMut(u64, u64) -> bool
+{
+    if candidates.is_empty() {
+        return Ok(candidates);
+    }
+
+    let wheel = candidates.wheel().clone();
+    let mut sieve = SegmentedSieve::new(&wheel, primes_page, candidates.from(), candidates.to());
+    let mut left = candidates.len() as u64;
 
-    for p in part.from..to {
-        if try!(is_coprime_to_list(p as u64, init_primes)) {
-            candidates.push(p as u64)
+    let mut position = 0;
+    while let Some(next) = candidates.next_index(position) {
+        sieve.skip_to(wheel.number_at(next));
+        let segment = match sieve.next() {
+            Some(segment) => segment,
+            None => break,
+        };
+
+        for index in segment.composites() {
+            if candidates.remove_index(index) {
+                left -= 1;
+            }
+        }
+        position = segment.end();
+        if !on_segment(wheel.number_at(position).min(candidates.to()), left) {
+            return Err(MathError::Cancelled);
         }
     }
 
+    candidates.retain_off_wheel(|&n| !has_factor_in(primes_page, n));
     Ok(candidates)
 }
 
-pub fn sieve_page(primes_page: &Vec<u64>, candidates: &Vec<u64>) -> Result<Vec<u64>, MathError> {
-    let mut sieved = Vec::new();
-    for &p in candidates {
-        if try!(is_coprime_to_list(p, primes_page)) {
-            sieved.push(p);
-        }
-    }
-    Ok(sieved)
+fn has_factor_in(primes: &[u64], n: u64) -> bool {
+    primes.iter()
+        .take_while(|&&p| p.checked_mul(p).is_some_and(|square| square <= n))
+        .any(|&p| n.is_multiple_of(p))
 }
 
-pub fn best_partitioning(from: usize, to: usize, parts: usize) -> Vec<Option<Partition>> {
+pub fn best_partitioning(from: u64, to: u64, parts: usize) -> Vec<Option<Partition>> {
     let mut partitions = Vec::with_capacity(parts);
 
     let mut distance = to - from;
-    let delta = (distance as f64 / parts as f64).ceil() as usize;
-
-    for part in 0..parts {
-            let from = from + delta * part;
+    let delta = distance.div_ceil(parts as u64);
 
+    for part in 0..parts as u64 {
         if distance == 0 {
             partitions.push(None);
-        } else if distance < delta {
+            continue;
+        }
+
+        let from = from + delta * part;
+        if distance < delta {
             partitions.push(Some(Partition {
-                from: from,
+                from,
                 delta: distance,
             }));
             distance = 0;
         } else {
-            partitions.push(Some(Partition {
-                from: from,
-                delta: delta,
-            }));
+            partitions.push(Some(Partition { from, delta }));
             distance -= delta;
         }
     }
@@ -83,15 +145,20 @@ pub fn best_partitioning(from: usize, to: usize, parts: usize) -> Vec<Option<Par
     partitions
 }
 
+/// Everything below the square of the last known prime can be sieved with the known
+/// primes. A square beyond the u64 range simply leaves `max` as the limit.
 pub fn best_max_for_sieve(last_prime: u64, max: u64) -> Result<u64, MathError> {
-    if let Some(theo_limit) = last_prime.checked_square() {
-        if theo_limit <= max {
-            Ok(theo_limit)
-        } else {
-            Ok(max)
-        }
+    let limit = match last_prime.checked_square() {
+        Some(theo_limit) => theo_limit.min(max),
+        None => max,
+    };
+
+    if limit > last_prime {
+        Ok(limit)
     } else {
-        Err(MathError::Limit("Could not calculate square of a prime!".to_string()))
+        Err(MathError::Limit(format!("There is nothing left to sieve between {} and {}.",
+                                     last_prime,
+                                     max)))
     }
 }
 
@@ -101,16 +168,26 @@ pub trait CheckedSquare {
 
 impl CheckedSquare for u64 {
     fn checked_square(self) -> Option<u64> {
-        const MAX: u64 = u32MAX as u64;
-        if self < MAX { Some(self.pow(2)) } else { None }
+        let square = self as u128 * self as u128;
+        if square <= u64::MAX as u128 {
+            Some(square as u64)
+        } else {
+            None
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{best_partitioning, best_max_for_sieve};
+    use super::{best_partitioning, best_max_for_sieve, find_candidates, find_candidates_with,
+                sieve_page, sieve_page_with, CheckedSquare};
+    use sieve::math::MathError;
     use super::super::Partition;
+    use super::super::wheel::Wheel;
+    use super::super::candidates::CandidateSet;
+    use std::sync::Arc;
     use std::fmt::Debug;
+    use test_util::is_prime;
 
     #[test]
     fn best_partitioning_works_for_even() {
@@ -190,4 +267,136 @@ mod tests {
         let ans = best_max_for_sieve(11, 100).unwrap();
         assert_eq!(ans, 100)
     }
+
+    fn set(wheel_primes: usize, numbers: &[u64]) -> CandidateSet {
+        CandidateSet::from_numbers(Arc::new(Wheel::new(wheel_primes)), numbers).unwrap()
+    }
+
+    fn numbers(set: &CandidateSet) -> Vec<u64> {
+        set.iter().collect()
+    }
+
+    #[test]
+    fn find_candidates_needs_enough_init_primes() {
+        let part = Partition {
+            from: 10,
+            delta: 40,
+        };
+        assert!(find_candidates(&Arc::new(Wheel::new(3)), &[2, 3, 5], part).is_err());
+    }
+
+    #[test]
+    fn find_candidates_stops_when_told() {
+        let part = Partition {
+            from: 10,
+            delta: 40,
+        };
+        let mut positions = Vec::new();
+        let ans = find_candidates_with(&Arc::new(Wheel::new(3)), &[2, 3, 5, 7], part, |position, found| {
+            positions.push((position, found));
+            false
+        });
+        match ans {
+            Err(MathError::Cancelled) => assert_eq!(positions, vec![(50, 11)]),
+            _ => panic!("the sieve went on"),
+        }
+    }
+
+    #[test]
+    fn find_candidates_returns_primes_of_partition() {
+        let part = Partition {
+            from: 20,
+            delta: 29,
+        };
+        let ans = find_candidates(&Arc::new(Wheel::new(3)), &[2, 3, 5, 7], part).unwrap();
+        assert_eq!(numbers(&ans), vec![23, 29, 31, 37, 41, 43, 47]);
+    }
+
+    #[test]
+    fn find_candidates_keeps_wheel_primes() {
+        let part = Partition {
+            from: 0,
+            delta: 30,
+        };
+        let ans = find_candidates(&Arc::new(Wheel::new(5)), &[2, 3, 5], part).unwrap();
+        assert_eq!(numbers(&ans), vec![2, 3, 5, 7, 11, 13, 17, 19, 23, 29]);
+    }
+
+    #[test]
+    fn sieve_page_checks_candidates_off_the_wheel() {
+        let primes = [2, 3, 5, 7];
+        let ans = sieve_page(&primes, set(4, &[2, 3, 9, 10, 11, 49, 53])).unwrap();
+        assert_eq!(numbers(&ans), vec![2, 3, 11, 53]);
+    }
+
+    #[test]
+    fn sieve_page_handles_sparse_candidates() {
+        let primes = [2, 3, 5, 7, 11, 13];
+        let ans = sieve_page(&primes, set(3, &[101, 143, 1_000_003, 1_000_005])).unwrap();
+        assert_eq!(numbers(&ans), vec![101, 1_000_003]);
+    }
+
+    #[test]
+    fn sieve_page_reports_the_candidates_left() {
+        let mut left = Vec::new();
+        let ans = sieve_page_with(&[2, 3, 5, 7], set(3, &[49, 53, 77, 79, 91, 97]), |_, found| {
+            left.push(found);
+            true
+        });
+        assert_eq!(numbers(&ans.unwrap()), vec![53, 79, 97]);
+        assert_eq!(left.last(), Some(&3));
+    }
+
+    #[test]
+    fn find_candidates_works_beyond_u32() {
+        let from = u32::MAX as u64 - 500;
+        let init_primes: Vec<u64> = (2..65_600).filter(|&n| is_prime(n)).collect();
+        let part = Partition {
+            from,
+            delta: 1000,
+        };
+        let ans = find_candidates(&Arc::new(Wheel::new(5)), &init_primes, part).unwrap();
+
+        assert_eq!(numbers(&ans), (from..from + 1000).filter(|&n| is_prime(n)).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn checked_square_covers_u64() {
+        assert_eq!((u32::MAX as u64).checked_square(), Some(18446744065119617025));
+        assert_eq!((u32::MAX as u64 + 1).checked_square(), None);
+    }
+
+    #[test]
+    fn best_max_for_sieve_works_beyond_u32() {
+        let ans = best_max_for_sieve(4_294_967_311, u64::MAX).unwrap();
+        assert_eq!(ans, u64::MAX)
+    }
+
+    #[test]
+    fn best_max_for_sieve_fails_without_room() {
+        assert!(best_max_for_sieve(11, 11).is_err());
+    }
+
+    #[test]
+    fn best_partitioning_works_near_u64_max() {
+        let ans = best_partitioning(u64::MAX - 10, u64::MAX, 4);
+        let expected = vec![Some(Partition {
+                                from: u64::MAX - 10,
+                                delta: 3,
+                            }),
+                            Some(Partition {
+                                from: u64::MAX - 7,
+                                delta: 3,
+                            }),
+                            Some(Partition {
+                                from: u64::MAX - 4,
+                                delta: 3,
+                            }),
+                            Some(Partition {
+                                from: u64::MAX - 1,
+                                delta: 1,
+                            })];
+
+        assert_vec_eq(ans, expected);
+    }
 }
@@ -1,10 +1,28 @@
+#[allow(clippy::module_inception)]
 mod math;
-pub use self::math::find_candidates;
+pub use self::math::{find_candidates, find_candidates_with};
 pub use self::math::best_max_for_sieve;
-pub use self::math::sieve_page;
+pub use self::math::{sieve_page, sieve_page_with};
 pub use self::math::best_partitioning;
 pub use self::math::init_primes;
+mod segmented;
+mod wheel;
+pub use self::wheel::{Wheel, MAX_WHEEL_PRIMES, MIN_WHEEL_PRIMES};
+mod candidates;
+pub use self::candidates::CandidateSet;
 mod partition;
 pub use self::partition::Partition;
+mod counting;
+pub use self::counting::{prime_count, prime_count_memory};
+mod estimate;
+pub use self::estimate::{li, riemann_r, nth_prime_estimate};
+mod primality;
+pub use self::primality::{is_prime, mul_mod, pow_mod};
+mod factor;
+pub use self::factor::{factor, factor_rest, trial_divide};
+mod montgomery;
+pub use self::montgomery::Montgomery;
+mod bpsw;
+pub use self::bpsw::is_prime_u128;
 mod errors;
 pub use self::errors::MathError;
@@ -0,0 +1,164 @@
+// Montgomery arithmetic modulo an odd u128. Numbers are kept as a R mod n with
+// R = 2^128, which turns the reduction after a multiplication into shifts and
+// multiplications instead of a 256-bit division.
+
+/// Arithmetic modulo an odd n, on numbers in Montgomery form.
+pub struct Montgomery {
+    n: u128,
+    /// -n^(-1) mod R
+    n_neg_inv: u128,
+    /// R^2 mod n
+    r2: u128,
+}
+
+impl Montgomery {
+    /// n must be odd.
+    pub fn new(n: u128) -> Montgomery {
+        // Newton's iteration doubles the correct low bits: 3 bits for any odd n, then
+        // 6, 12, 24, 48, 96 and 192.
+        let mut inv = n;
+        for _ in 0..6 {
+            inv = inv.wrapping_mul(2u128.wrapping_sub(n.wrapping_mul(inv)));
+        }
+
+        let mut r2 = (u128::MAX % n + 1) % n;
+        for _ in 0..128 {
+            r2 = add_mod(r2, r2, n);
+        }
+
+        Montgomery {
+            n,
+            n_neg_inv: inv.wrapping_neg(),
+            r2,
+        }
+    }
+
+    pub fn modulus(&self) -> u128 {
+        self.n
+    }
+
+    pub fn to_mont(&self, a: u128) -> u128 {
+        self.mul(a % self.n, self.r2)
+    }
+
+    pub fn from_mont(&self, a: u128) -> u128 {
+        self.reduce(0, a)
+    }
+
+    /// 1 in Montgomery form.
+    pub fn one(&self) -> u128 {
+        (u128::MAX % self.n + 1) % self.n
+    }
+
+    pub fn mul(&self, a: u128, b: u128) -> u128 {
+        let (hi, lo) = mul_wide(a, b);
+        self.reduce(hi, lo)
+    }
+
+    pub fn add(&self, a: u128, b: u128) -> u128 {
+        add_mod(a, b, self.n)
+    }
+
+    pub fn sub(&self, a: u128, b: u128) -> u128 {
+        if a >= b { a - b } else { self.n - (b - a) }
+    }
+
+    /// a / 2
+    pub fn half(&self, a: u128) -> u128 {
+        if a & 1 == 0 {
+            a >> 1
+        } else {
+            // (a + n) / 2 without overflowing, both are odd.
+            (a >> 1) + (self.n >> 1) + 1
+        }
+    }
+
+    pub fn pow(&self, base: u128, mut exp: u128) -> u128 {
+        let mut result = self.one();
+        let mut base = base;
+        while exp > 0 {
+            if exp & 1 == 1 {
+                result = self.mul(result, base);
+            }
+            base = self.mul(base, base);
+            exp >>= 1;
+        }
+        result
+    }
+
+    /// (hi R + lo) / R mod n, for hi R + lo < n R.
+    fn reduce(&self, hi: u128, lo: u128) -> u128 {
+        let m = lo.wrapping_mul(self.n_neg_inv);
+        let (m_hi, m_lo) = mul_wide(m, self.n);
+        // lo + m_lo is 0 mod R by the choice of m, so it only carries.
+        let carry = lo.overflowing_add(m_lo).1 as u128;
+        let (sum, overflow) = hi.overflowing_add(m_hi);
+        let (sum, overflow_carry) = sum.overflowing_add(carry);
+        if overflow || overflow_carry || sum >= self.n {
+            sum.wrapping_sub(self.n)
+        } else {
+            sum
+        }
+    }
+}
+
+fn add_mod(a: u128, b: u128, n: u128) -> u128 {
+    let (sum, overflow) = a.overflowing_add(b);
+    if overflow || sum >= n {
+        sum.wrapping_sub(n)
+    } else {
+        sum
+    }
+}
+
+/// The full 256-bit product as (high, low) halves.
+fn mul_wide(a: u128, b: u128) -> (u128, u128) {
+    const MASK: u128 = u64::MAX as u128;
+    let (a_hi, a_lo) = (a >> 64, a & MASK);
+    let (b_hi, b_lo) = (b >> 64, b & MASK);
+
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let hi_hi = a_hi * b_hi;
+
+    let middle = (lo_lo >> 64) + (hi_lo & MASK) + (lo_hi & MASK);
+    let lo = (middle << 64) | (lo_lo & MASK);
+    let hi = hi_hi + (hi_lo >> 64) + (lo_hi >> 64) + (middle >> 64);
+    (hi, lo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mul_wide, Montgomery};
+
+    #[test]
+    fn mul_wide_keeps_every_bit() {
+        assert_eq!(mul_wide(u128::MAX, u128::MAX), (u128::MAX - 1, 1));
+        assert_eq!(mul_wide(1 << 64, 1 << 64), (1, 0));
+        assert_eq!(mul_wide(12345, 67890), (0, 12345 * 67890));
+    }
+
+    #[test]
+    fn multiplies_like_plain_arithmetic() {
+        let m = Montgomery::new(1_000_000_007);
+        for &(a, b) in &[(0, 5), (1, 1), (123_456_789, 987_654_321), (1_000_000_006, 1_000_000_006)] {
+            let product = m.from_mont(m.mul(m.to_mont(a), m.to_mont(b)));
+            assert_eq!(product, a * b % 1_000_000_007);
+        }
+        assert_eq!(m.from_mont(m.one()), 1);
+        assert_eq!(m.from_mont(m.half(m.to_mont(3))), 500_000_005);
+    }
+
+    #[test]
+    fn works_for_moduli_near_u128_max() {
+        let n = u128::MAX - 158; // 2^128 - 159 is prime
+        let m = Montgomery::new(n);
+        let a = m.to_mont(n - 1);
+        assert_eq!(m.from_mont(m.mul(a, a)), 1);
+        // Fermat: 3^(n-1) = 1 mod n
+        assert_eq!(m.from_mont(m.pow(m.to_mont(3), n - 1)), 1);
+        assert_eq!(m.from_mont(m.add(a, m.to_mont(2))), 1);
+        assert_eq!(m.from_mont(m.sub(m.to_mont(1), a)), 2);
+    }
+}
@@ -2,8 +2,8 @@ use std::fmt::{Debug, Formatter, Error};
 use std::cmp::PartialEq;
 
 pub struct Partition {
-    pub from: usize,
-    pub delta: usize,
+    pub from: u64,
+    pub delta: u64,
 }
 
 impl Clone for Partition {
@@ -30,8 +30,4 @@ impl PartialEq<Partition> for Partition {
     fn eq(&self, other: &Partition) -> bool {
         self.from == other.from && self.delta == other.delta
     }
-
-    fn ne(&self, other: &Partition) -> bool {
-        !(self == other)
-    }
 }
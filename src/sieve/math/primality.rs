@@ -0,0 +1,106 @@
+// Deterministic Miller–Rabin for 64-bit numbers. With the seven bases found by Jim
+// Sinclair no composite below 2^64 passes, so the answer is exact.
+
+const BASES: [u64; 7] = [2, 325, 9375, 28178, 450775, 9780504, 1795265022];
+
+// Primes to try dividing by before testing, also the answer for small numbers.
+const SMALL_PRIMES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+/// Whether n is prime, exactly, for every u64.
+pub fn is_prime(n: u64) -> bool {
+    for &p in SMALL_PRIMES.iter() {
+        if n == p {
+            return true;
+        }
+        if n.is_multiple_of(p) {
+            return false;
+        }
+    }
+    if n < 41 * 41 {
+        return n > 1;
+    }
+
+    // n - 1 = d 2^s with d odd
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+    BASES.iter().all(|&base| passes(n, d, s, base % n))
+}
+
+/// Whether n is a strong probable prime to the base.
+fn passes(n: u64, d: u64, s: u32, base: u64) -> bool {
+    if base == 0 {
+        return true;
+    }
+
+    let mut x = pow_mod(base, d, n);
+    if x == 1 || x == n - 1 {
+        return true;
+    }
+    for _ in 1..s {
+        x = mul_mod(x, x, n);
+        if x == n - 1 {
+            return true;
+        }
+    }
+    false
+}
+
+/// a b mod m, without overflow.
+pub fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    (a as u128 * b as u128 % m as u128) as u64
+}
+
+/// base^exp mod m, without overflow.
+pub fn pow_mod(mut base: u64, mut exp: u64, m: u64) -> u64 {
+    let mut result = 1 % m;
+    base %= m;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_mod(result, base, m);
+        }
+        base = mul_mod(base, base, m);
+        exp >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_prime, mul_mod, pow_mod};
+    use test_util::is_prime as by_trial_division;
+
+    #[test]
+    fn agrees_with_trial_division() {
+        for n in 0..100_000 {
+            assert_eq!(is_prime(n), by_trial_division(n), "{}", n);
+        }
+    }
+
+    #[test]
+    fn rejects_strong_pseudoprimes() {
+        // Strong pseudoprimes to several small bases, and Carmichael numbers.
+        let composites = [561, 1_373_653, 25_326_001, 3_215_031_751, 2_152_302_898_747,
+                          3_474_749_660_383, 341_550_071_728_321, 3_825_123_056_546_413_051,
+                          18_446_744_073_709_551_615, 4_294_967_297];
+        for &n in composites.iter() {
+            assert!(!is_prime(n), "{}", n);
+        }
+    }
+
+    #[test]
+    fn accepts_big_primes() {
+        let primes = [4_294_967_311, 1_000_000_000_000_000_003, 18_446_744_073_709_551_557,
+                      9_223_372_036_854_775_783];
+        for &n in primes.iter() {
+            assert!(is_prime(n), "{}", n);
+        }
+        assert!(!is_prime(18_446_744_073_709_551_557 - 2));
+    }
+
+    #[test]
+    fn modular_arithmetic_does_not_overflow() {
+        assert_eq!(mul_mod(u64::MAX - 1, u64::MAX - 1, u64::MAX), 1);
+        assert_eq!(pow_mod(2, 64, u64::MAX), 1);
+        assert_eq!(pow_mod(7, 0, 1), 0);
+    }
+}
@@ -0,0 +1,177 @@
+use std::vec::Vec;
+use config::SEGMENT_SIZE;
+use sieve::math::wheel::Wheel;
+
+/// One window of a segmented sieve, covering consecutive spokes of the wheel.
+/// `composite[i]` is set when the spoke with wheel index `start + i` was crossed off
+/// by one of the sieving primes.
+pub struct Segment {
+    pub start: u64,
+    pub composite: Vec<bool>,
+}
+
+impl Segment {
+    /// Wheel index after the last spoke of this segment.
+    pub fn end(&self) -> u64 {
+        self.start + self.composite.len() as u64
+    }
+
+    /// Wheel indices of the spokes that survived the sieve.
+    pub fn survivors<'a>(&'a self) -> impl Iterator<Item = u64> + 'a {
+        self.indices_where(false)
+    }
+
+    /// Wheel indices of the spokes that were crossed off.
+    pub fn composites<'a>(&'a self) -> impl Iterator<Item = u64> + 'a {
+        self.indices_where(true)
+    }
+
+    fn indices_where<'a>(&'a self, crossed_off: bool) -> impl Iterator<Item = u64> + 'a {
+        let start = self.start;
+        self.composite
+            .iter()
+            .enumerate()
+            .filter(move |&(_, &composite)| composite == crossed_off)
+            .map(move |(i, _)| start + i as u64)
+    }
+}
+
+/// Sieve of Eratosthenes over the spokes of a wheel in `[from, to)`, worked through
+/// `SEGMENT_SIZE` spokes at a time so that the crossing off stays in cache. For every
+/// sieving prime the index of the next multiplier is carried over from one segment to
+/// the next. Numbers off the wheel are never visited.
+///
+/// Multiples are crossed off from `p²` on, so a sieving prime inside the range is
+/// never removed by itself.
+pub struct SegmentedSieve<'a> {
+    wheel: &'a Wheel,
+    primes: Vec<u64>,
+    next_factors: Vec<u64>, // wheel index of the multiplier of the next multiple
+    position: u64,
+    end: u64,
+}
+
+impl<'a> SegmentedSieve<'a> {
+    /// `primes` must be in ascending order. The wheel primes and primes whose square
+    /// is not below `to` cannot cross off any spoke and are ignored.
+    pub fn new(wheel: &'a Wheel, primes: &[u64], from: u64, to: u64) -> SegmentedSieve<'a> {
+        let primes: Vec<u64> = primes.iter()
+            .cloned()
+            .take_while(|&p| p.checked_mul(p).is_some_and(|square| square < to))
+            .filter(|&p| !wheel.modulus().is_multiple_of(p))
+            .collect();
+
+        SegmentedSieve {
+            wheel,
+            next_factors: primes.iter().map(|&p| wheel.index_of(p)).collect(),
+            primes,
+            position: wheel.index_of(from),
+            end: wheel.index_of(to),
+        }
+    }
+
+    /// Moves the sieve forward to `n` without sieving the numbers in between.
+    pub fn skip_to(&mut self, n: u64) {
+        self.position = self.position.max(self.wheel.index_of(n));
+    }
+}
+
+impl<'a> Iterator for SegmentedSieve<'a> {
+    type Item = Segment;
+
+    fn next(&mut self) -> Option<Segment> {
+        if self.position >= self.end {
+            return None;
+        }
+
+        let wheel = self.wheel;
+        let start = self.position;
+        let len = (self.end - start).min(SEGMENT_SIZE as u64);
+        let low = wheel.number_at(start);
+        let high = wheel.number_at(start + len);
+        let mut composite = vec![false; len as usize];
+
+        for (&p, next) in self.primes.iter().zip(self.next_factors.iter_mut()) {
+            if p.saturating_mul(wheel.number_at(*next)) < low {
+                *next = wheel.index_of(low.div_ceil(p));
+            }
+            loop {
+                let multiple = p.saturating_mul(wheel.number_at(*next));
+                if multiple >= high {
+                    break;
+                }
+                composite[(wheel.index_of(multiple) - start) as usize] = true;
+                *next += 1;
+            }
+        }
+
+        self.position = start + len;
+        Some(Segment { start, composite })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SegmentedSieve;
+    use config::SEGMENT_SIZE;
+    use sieve::math::wheel::Wheel;
+    use test_util::is_prime;
+
+    fn base_primes(limit: u64) -> Vec<u64> {
+        (2..limit).filter(|&n| is_prime(n)).collect()
+    }
+
+    fn sieve(wheel: &Wheel, primes: &[u64], from: u64, to: u64) -> Vec<u64> {
+        SegmentedSieve::new(wheel, primes, from, to)
+            .flat_map(|segment| segment.survivors().collect::<Vec<u64>>())
+            .map(|index| wheel.number_at(index))
+            .collect()
+    }
+
+    #[test]
+    fn sieves_over_several_segments() {
+        let primes = base_primes(1000);
+        for no_primes in 3..6 {
+            let wheel = Wheel::new(no_primes);
+            let to = 3 * SEGMENT_SIZE as u64 * wheel.modulus() / wheel.spokes() + 17;
+            let expected: Vec<u64> = (2..to)
+                .filter(|&n| is_prime(n) && !wheel.primes().contains(&n))
+                .collect();
+
+            assert_eq!(sieve(&wheel, &primes, 2, to), expected);
+        }
+    }
+
+    #[test]
+    fn sieves_range_not_starting_on_a_turn() {
+        let wheel = Wheel::new(4);
+        let primes = base_primes(400);
+        assert_eq!(sieve(&wheel, &primes, 100_003, 150_001),
+                   (100_003..150_001).filter(|&n| is_prime(n)).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn skipping_keeps_offsets_correct() {
+        let wheel = Wheel::new(3);
+        let primes = base_primes(800);
+        let mut sieve = SegmentedSieve::new(&wheel, &primes, 1000, 600_000);
+        let _ = sieve.next();
+        sieve.skip_to(400_003);
+        let segment = sieve.next().unwrap();
+
+        assert_eq!(wheel.number_at(segment.start), 400_003);
+        for index in segment.start..segment.end() {
+            let n = wheel.number_at(index);
+            let composite = segment.composite[(index - segment.start) as usize];
+            assert_eq!(composite, !is_prime(n), "n = {}", n);
+        }
+    }
+
+    #[test]
+    fn sieving_primes_are_not_crossed_off() {
+        let wheel = Wheel::new(3);
+        let primes = vec![2, 3, 5, 7];
+        assert_eq!(sieve(&wheel, &primes, 2, 49),
+                   vec![7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47]);
+    }
+}
@@ -0,0 +1,129 @@
+use std::vec::Vec;
+use sieve::math::init_primes;
+
+/// Smallest and largest number of primes a wheel is built from (mod 30 and mod 2310).
+pub const MIN_WHEEL_PRIMES: usize = 3;
+pub const MAX_WHEEL_PRIMES: usize = 5;
+
+/// A wheel modulo the product of the first few primes. Only the numbers coprime to
+/// the modulus, the spokes, can be primes bigger than the wheel primes, so the sieve
+/// only ever visits and stores spokes. Spokes are numbered consecutively from 0.
+pub struct Wheel {
+    primes: Vec<u64>,
+    modulus: u64,
+    residues: Vec<u32>,
+    ranks: Vec<u32>, // ranks[r] = number of residues below r
+}
+
+impl Wheel {
+    /// Builds the wheel of the first `no_primes` primes of `init_primes()`.
+    pub fn new(no_primes: usize) -> Wheel {
+        let no_primes = no_primes.clamp(MIN_WHEEL_PRIMES, MAX_WHEEL_PRIMES);
+        let primes: Vec<u64> = init_primes().into_iter().take(no_primes).collect();
+        let modulus: u64 = primes.iter().product();
+
+        let mut residues = Vec::new();
+        let mut ranks = Vec::with_capacity(modulus as usize);
+        for r in 0..modulus {
+            ranks.push(residues.len() as u32);
+            if primes.iter().all(|&p| !r.is_multiple_of(p)) {
+                residues.push(r as u32);
+            }
+        }
+
+        Wheel {
+            primes,
+            modulus,
+            residues,
+            ranks,
+        }
+    }
+
+    /// Picks the biggest wheel whose lookup tables take at most 1/1024 of `budget`
+    /// bytes. Bigger wheels skip more numbers but their tables compete with the
+    /// segments for the cache.
+    pub fn for_memory(budget: usize) -> Wheel {
+        let no_primes = (MIN_WHEEL_PRIMES..MAX_WHEEL_PRIMES + 1)
+            .rev()
+            .find(|&n| table_size(n) * 1024 <= budget)
+            .unwrap_or(MIN_WHEEL_PRIMES);
+        Wheel::new(no_primes)
+    }
+
+    pub fn primes(&self) -> &[u64] {
+        &self.primes
+    }
+
+    pub fn modulus(&self) -> u64 {
+        self.modulus
+    }
+
+    /// Number of spokes per turn of the wheel.
+    pub fn spokes(&self) -> u64 {
+        self.residues.len() as u64
+    }
+
+    pub fn is_spoke(&self, n: u64) -> bool {
+        let r = (n % self.modulus) as usize;
+        let next_rank = self.ranks.get(r + 1).map_or(self.spokes(), |&rank| rank as u64);
+        next_rank > self.ranks[r] as u64
+    }
+
+    /// Number of spokes below `n`, which is also the index of the first spoke ≥ `n`.
+    pub fn index_of(&self, n: u64) -> u64 {
+        (n / self.modulus) * self.spokes() + self.ranks[(n % self.modulus) as usize] as u64
+    }
+
+    /// The spoke with the given index, saturating at `u64::MAX`.
+    pub fn number_at(&self, index: u64) -> u64 {
+        let turn = index / self.spokes();
+        let residue = self.residues[(index % self.spokes()) as usize] as u64;
+        turn.saturating_mul(self.modulus).saturating_add(residue)
+    }
+}
+
+/// Bytes taken by the lookup tables of the wheel of the first `no_primes` primes.
+fn table_size(no_primes: usize) -> usize {
+    let primes = init_primes();
+    let modulus: u64 = primes.iter().take(no_primes).product();
+    let spokes: u64 = primes.iter().take(no_primes).map(|p| p - 1).product();
+    (modulus + spokes) as usize * 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Wheel;
+
+    #[test]
+    fn wheel_sizes() {
+        assert_eq!((Wheel::new(3).modulus(), Wheel::new(3).spokes()), (30, 8));
+        assert_eq!((Wheel::new(4).modulus(), Wheel::new(4).spokes()), (210, 48));
+        assert_eq!((Wheel::new(5).modulus(), Wheel::new(5).spokes()), (2310, 480));
+    }
+
+    #[test]
+    fn for_memory_picks_biggest_fitting_wheel() {
+        assert_eq!(Wheel::for_memory(1000).modulus(), 30);
+        assert_eq!(Wheel::for_memory(2 << 20).modulus(), 210);
+        assert_eq!(Wheel::for_memory(1 << 27).modulus(), 2310);
+    }
+
+    #[test]
+    fn index_and_number_are_inverse_on_spokes() {
+        let wheel = Wheel::new(4);
+        let spokes: Vec<u64> = (0..1000).filter(|&n| wheel.is_spoke(n)).collect();
+
+        for (i, &n) in spokes.iter().enumerate() {
+            assert_eq!(wheel.number_at(i as u64), n);
+            assert_eq!(wheel.index_of(n), i as u64);
+        }
+        assert!(spokes.iter().all(|&n| [2, 3, 5, 7].iter().all(|p| !n.is_multiple_of(*p))));
+        assert_eq!(spokes.len() as u64, wheel.index_of(1000));
+    }
+
+    #[test]
+    fn number_at_saturates() {
+        let wheel = Wheel::new(3);
+        assert_eq!(wheel.number_at(u64::MAX), u64::MAX);
+    }
+}
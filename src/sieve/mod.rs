@@ -1,6 +1,10 @@
 pub mod math;
 pub mod pool;
-pub use self::pool::{ThreadPool, ThreadPoolError, ThreadError};
+pub use self::pool::{ThreadPool, ThreadPoolError};
+pub mod cancel;
+pub use self::cancel::CancelToken;
+pub mod progress;
+pub use self::progress::{NoProgress, Progress, ProgressSink};
 mod worker;
-pub use self::worker::{MsgFromWorker, MsgToWorker};
 mod thread;
+mod affinity;
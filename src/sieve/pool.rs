@@ -1,139 +1,545 @@
-use std::sync::mpsc::{SendError, RecvError};
+use std::cell::{Cell, RefCell};
+use std::sync::mpsc::{channel, Receiver, Sender, SendError, RecvError, RecvTimeoutError};
 use std::sync::Arc;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::result::Result;
 use std::vec::Vec;
+use std::time::Duration;
 use sieve::worker::{new_worker, MsgToWorker, MsgFromWorker};
-use sieve::thread::{Thread, Send, Receive};
+use sieve::thread::{Answer, Thread, Send};
+use sieve::affinity::allowed_cpus;
+use sieve::cancel::CancelToken;
+use sieve::progress::{NoProgress, Progress, ProgressSink};
 use sieve::math;
-use sieve::math::{Partition, MathError};
+use sieve::math::{CandidateSet, MathError, Partition, Wheel};
+use config::{Config, ConfigError};
+
+/// How often an instruction is handed to a fresh worker after the one working on it
+/// died.
+pub const MAX_RETRIES: usize = 2;
+
+/// How long the pool waits for an answer before it looks for workers that died
+/// without one.
+const DEAD_WORKER_CHECK: Duration = Duration::from_millis(100);
+
+/// A round is cut into this many work units per thread. Threads that finish early
+/// take the next unit instead of waiting for the slowest one.
+pub const UNITS_PER_THREAD: usize = 8;
 
 pub struct ThreadPool {
-    threads: Vec<Thread>,
-    max_ppt: usize, // ppt = prime per thread
+    // Dead workers are replaced while a request runs, hence the cell.
+    threads: RefCell<Vec<Thread>>,
+    answers: Receiver<Answer>,
+    answer_to: Sender<Answer>, // handed to every new worker
+    wheel: Arc<Wheel>,
+    cpus: Vec<usize>, // pinned to in turn, none for free workers
+    max_ppt: usize, // ppt = prime per thread, also the width of a candidate round
+    progress: RefCell<Box<dyn ProgressSink>>,
+    in_stages: Cell<bool>, // inside as_one_request
+    cancel: CancelToken, // shared with every worker
+}
+
+/// An instruction handed to a worker, kept to be retried if the worker dies on it.
+struct Unit {
+    id: usize,
+    instruction: MsgToWorker,
+    partition: Partition,
+    retries: usize,
+    position: u64, // as last reported
+    found: u64,
 }
 
 impl ThreadPool {
+    /// Starts `no_threads` workers that may use `max_ppt` bytes each, with the rest
+    /// of the default config. A pool needs a worker, so zero threads start one.
     pub fn new(no_threads: usize, max_ppt: usize) -> ThreadPool {
-        let mut threads = Vec::with_capacity(no_threads);
-        for _ in 0..no_threads {
-            threads.push(new_worker());
+        let no_threads = no_threads.max(1);
+        let config = Config {
+            cores: no_threads,
+            max_mem_usage: no_threads.saturating_mul(max_ppt),
+            pin_cores: false,
+            ..Config::default()
+        };
+        ThreadPool::start(&config, Vec::new()).0
+    }
+
+    /// Starts one worker per core of the config, each with its share of the memory.
+    /// With `pin_cores` the workers are pinned to the allowed CPUs in turn, and the
+    /// pool fails to start unless every one of them is. Fails as well if the config
+    /// does not pass `Config::check`.
+    pub fn from_config(config: &Config) -> Result<ThreadPool, ThreadPoolError> {
+        config.check()?;
+        if !config.pin_cores {
+            return Ok(ThreadPool::start(config, Vec::new()).0);
         }
+        let cpus = allowed_cpus();
+        if cpus.is_empty() {
+            return Err(ThreadPoolError::Unpinned(Vec::new()));
+        }
+        match ThreadPool::start(config, cpus) {
+            (pool, ref unpinned) if unpinned.is_empty() => Ok(pool),
+            (_, unpinned) => Err(ThreadPoolError::Unpinned(unpinned)),
+        }
+    }
+
+    /// Starts the workers, pinned to the CPUs in turn if there are any, and returns
+    /// the CPUs that a worker could not be pinned to.
+    fn start(config: &Config, cpus: Vec<usize>) -> (ThreadPool, Vec<usize>) {
+        let no_threads = config.cores;
+        let max_ppt = config.mem_per_thread();
+        let wheel = Arc::new(match config.wheel_primes {
+            Some(no_primes) => Wheel::new(no_primes),
+            None => Wheel::for_memory(max_ppt),
+        });
+
+        let (answer_to, answers) = channel();
+        let mut pool = ThreadPool {
+            threads: RefCell::new(Vec::with_capacity(no_threads)),
+            answers,
+            answer_to,
+            wheel,
+            cpus,
+            max_ppt,
+            progress: RefCell::new(Box::new(NoProgress)),
+            in_stages: Cell::new(false),
+            cancel: CancelToken::new(),
+        };
+        let mut unpinned = Vec::new();
+        for i in 0..no_threads {
+            let (thread, pinned) = pool.spawn(i);
+            pool.threads.get_mut().push(thread);
+            unpinned.extend(pinned.err());
+        }
+        (pool, unpinned)
+    }
 
-        ThreadPool {
-            threads: threads,
-            max_ppt: max_ppt,
+    /// A new worker for the i-th place. Fails with its CPU if it could not be pinned,
+    /// in which case it runs wherever the scheduler puts it.
+    fn spawn(&self, i: usize) -> (Thread, Result<(), usize>) {
+        let cpu = if self.cpus.is_empty() {
+            None
+        } else {
+            Some(self.cpus[i % self.cpus.len()])
+        };
+        let (thread, pinned) = new_worker(i,
+                                          self.wheel.clone(),
+                                          cpu,
+                                          self.answer_to.clone(),
+                                          self.cancel.clone());
+        match cpu {
+            Some(cpu) if !pinned => (thread, Err(cpu)),
+            _ => (thread, Ok(())),
         }
     }
 
-    pub fn find_candidates(&self, init_primes: Vec<u64>) -> Result<Vec<u64>, ThreadPoolError> {
+    /// Replaces the i-th worker, which died, and returns how it died if it panicked
+    /// outside of an instruction. Its CPU took a worker when the pool started, so
+    /// the replacement is not expected to fail pinning; if it does it still runs.
+    fn respawn(&self, i: usize) -> Option<String> {
+        let dead = ::std::mem::replace(&mut self.threads.borrow_mut()[i], self.spawn(i).0);
+        dead.join().err()
+    }
+
+    /// Sends the progress of every request from now on to the sink.
+    pub fn set_progress_sink(&mut self, sink: Box<dyn ProgressSink>) {
+        self.progress = RefCell::new(sink);
+    }
+
+    /// The token that cancels the requests of this pool. The cancel and any deadline
+    /// are taken back once a request ends, so the pool is ready for the next one.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Makes the requests in `requests` one for the progress sink: it is started once
+    /// with `request`, each request is a stage of it with numbers of its own, and the
+    /// sink is finished when `requests` returns. It is one request for the cancel
+    /// token too.
+    pub fn as_one_request<F, R>(&self, request: &str, requests: F) -> R
+        where F: FnOnce() -> R
+    {
+        self.progress.borrow_mut().start(request, 0);
+        self.in_stages.set(true);
+        let result = requests();
+        self.in_stages.set(false);
+        self.progress.borrow_mut().finish();
+        self.cancel.reset();
+        result
+    }
+
+    fn no_threads(&self) -> usize {
+        self.threads.borrow().len()
+    }
+
+    /// Finds the candidates above the last initial prime. The answer holds one set per
+    /// work unit of the range, in ascending order.
+    pub fn find_candidates(&self,
+                           init_primes: Vec<u64>)
+                           -> Result<Vec<CandidateSet>, ThreadPoolError> {
         let &last_prime = init_primes.last().unwrap();
-        let max = try!(math::best_max_for_sieve(last_prime, self.max_ppt as u64));
-        let partitions =
-            math::best_partitioning(last_prime as usize, max as usize, self.threads.len());
+        self.find_candidates_from(init_primes, last_prime + 1)
+    }
 
-        self.send_find_candidates_instruction(init_primes, partitions)
-            .and_then(|running_threads| self.recv_candidate_results(running_threads))
-            .map_err(ThreadPoolError::Thread)
+    /// Like `find_candidates`, but the round starts at `from`. The round ends before
+    /// the square of the last initial prime, so the candidates only need sieving by
+    /// primes that are not initial.
+    pub fn find_candidates_from(&self,
+                                init_primes: Vec<u64>,
+                                from: u64)
+                                -> Result<Vec<CandidateSet>, ThreadPoolError> {
+        let &last_prime = init_primes.last().unwrap();
+        let units = self.round_units(last_prime, from)?;
+        self.find_candidates_in(init_primes, units, |_, _| {})
     }
 
-    fn send_find_candidates_instruction(&self,
-                                        init_primes: Vec<u64>,
-                                        partitions: Vec<Option<Partition>>)
-                                        -> Result<Vec<&Thread>, Vec<ThreadError>> {
-        let primes = Arc::new(init_primes);
-        let mut running_threads = Vec::with_capacity(self.threads.len());
-        let mut errors = vec![];
-        for (thread, partition) in self.threads.iter().zip(partitions) {
-            if let Some(partition) = partition.clone() {
-                let result = thread.send(MsgToWorker::FindCandidates(primes.clone(), partition));
-                if let Err(err) = result {
-                    errors.push(ThreadError::SendError(err));
-                }
-                running_threads.push(thread);
-            }
+    /// The work units of the round from `from` that the initial primes up to
+    /// `last_prime` find the candidates of, ascending and without gaps.
+    pub fn round_units(&self,
+                       last_prime: u64,
+                       from: u64)
+                       -> Result<Vec<Partition>, ThreadPoolError> {
+        let window_end = from.saturating_add(self.max_ppt as u64);
+        let max = math::best_max_for_sieve(last_prime, window_end)?;
+        if max <= from {
+            return Err(ThreadPoolError::Math(MathError::Limit(format!("The initial primes \
+                                                                        up to {} cannot \
+                                                                        sieve above {}.",
+                                                                       last_prime,
+                                                                       from))));
         }
+        let units = self.no_threads() * UNITS_PER_THREAD;
+        Ok(math::best_partitioning(from, max, units).into_iter().flatten().collect())
+    }
+
+    /// Finds the candidates in each of the units, one set per unit in the same order.
+    /// Every set is handed to `completed` with the position of its unit as soon as
+    /// it is found, so it can be kept before the rest of the request is done.
+    pub fn find_candidates_in<F>(&self,
+                                 init_primes: Vec<u64>,
+                                 units: Vec<Partition>,
+                                 completed: F)
+                                 -> Result<Vec<CandidateSet>, ThreadPoolError>
+        where F: FnMut(usize, &CandidateSet)
+    {
+        let primes = Arc::new(init_primes);
+        let instructions = units.into_iter()
+            .map(|p| MsgToWorker::FindCandidates(primes.clone(), p));
+
+        self.dispatch(instructions,
+                      "finding candidate primes",
+                      |resp| match resp {
+                          MsgFromWorker::CandidatesResult(candidates) => Ok(candidates),
+                          resp => Err(resp),
+                      },
+                      completed)
+    }
+
+    /// Sieves every set of candidates as a work unit of its own and returns the sets
+    /// in the same order.
+    pub fn sieve(&self,
+                 prime_page: Vec<u64>,
+                 candidates: Vec<CandidateSet>)
+                 -> Result<Vec<CandidateSet>, ThreadPoolError> {
+        self.sieve_with(prime_page, candidates, |_, _| {})
+    }
+
+    /// Like `sieve`, but every sieved set is handed to `completed` with its position
+    /// as soon as it is done.
+    pub fn sieve_with<F>(&self,
+                         prime_page: Vec<u64>,
+                         candidates: Vec<CandidateSet>,
+                         completed: F)
+                         -> Result<Vec<CandidateSet>, ThreadPoolError>
+        where F: FnMut(usize, &CandidateSet)
+    {
+        let primes = Arc::new(prime_page);
+        let instructions = candidates.into_iter()
+            .map(|set| MsgToWorker::Sieve(primes.clone(), Arc::new(set)));
 
-        if errors.len() > 0 {
-            Err(errors)
+        self.dispatch(instructions,
+                      "sieving candidates",
+                      |resp| match resp {
+                          MsgFromWorker::SieveResult(sieved) => Ok(sieved),
+                          resp => Err(resp),
+                      },
+                      completed)
+    }
+
+    /// The wheel of the workers, which the candidate sets they answer with are on.
+    pub fn wheel(&self) -> &Arc<Wheel> {
+        &self.wheel
+    }
+
+    /// Works through the instructions as units: every thread takes the next one as
+    /// soon as it has answered its last, and the answers are put back in
+    /// instruction order. `unpack` extracts the result from the expected response
+    /// and gives back anything else. A thread that dies on a unit, whether it says
+    /// so or just ends, is replaced, and the fresh one tries again up to
+    /// `MAX_RETRIES` times. Runs until every unit
+    /// is answered even after a failure, so that no stale answer is left for the next
+    /// request. Progress reports of the workers go to the progress sink, and every
+    /// result to `completed` with the position of its instruction as it comes in.
+    /// Once the request is cancelled no more units are handed out, and the running
+    /// ones are waited for.
+    fn dispatch<I, F, G, T>(&self,
+                            instructions: I,
+                            request: &str,
+                            unpack: F,
+                            mut completed: G)
+                            -> Result<Vec<T>, ThreadPoolError>
+        where I: Iterator<Item = MsgToWorker>,
+              F: Fn(MsgFromWorker) -> Result<T, MsgFromWorker>,
+              G: FnMut(usize, &T)
+    {
+        let instructions: Vec<MsgToWorker> = instructions.collect();
+        let no_units = instructions.len();
+        let total = instructions.iter().map(|instruction| instruction.partition().delta).sum();
+        let mut progress = self.progress.borrow_mut();
+        let in_stages = self.in_stages.get();
+        if in_stages {
+            progress.stage(request, total);
         } else {
-            Ok(running_threads)
+            progress.start(request, total);
         }
-    }
 
-    fn recv_candidate_results(&self,
-                              running_threads: Vec<&Thread>)
-                              -> Result<Vec<u64>, Vec<ThreadError>> {
-        let mut found_candidates = Vec::with_capacity(running_threads.len());
+        let mut queue = instructions.into_iter().enumerate();
+        let mut working: Vec<Option<Unit>> = (0..self.no_threads()).map(|_| None).collect();
+        let mut results: Vec<Option<T>> = Vec::new();
         let mut errors = vec![];
-        for thread in running_threads.iter() {
-            match thread.recv() {
-                Ok(MsgFromWorker::CandidatesResult(mut candidates)) => {
-                    found_candidates.append(&mut candidates);
+        let (mut processed, mut found) = (0, 0); // by the finished units
+        let mut cancelled = false;
+
+        for (i, place) in working.iter_mut().enumerate() {
+            *place = self.hand_out(i, &mut queue, &mut results, &mut errors);
+        }
+
+        while working.iter().any(Option::is_some) {
+            let (i, resp) = match self.answers.recv_timeout(DEAD_WORKER_CHECK) {
+                Ok(answer) => answer,
+                Err(RecvTimeoutError::Timeout) => {
+                    let threads = self.threads.borrow();
+                    let dead = (0..working.len())
+                        .find(|&i| working[i].is_some() && threads[i].is_finished());
+                    match (dead, self.answers.try_recv()) {
+                        // What a thread sent before it ended is in the channel by now.
+                        (_, Ok(answer)) => answer,
+                        (Some(i), Err(_)) => {
+                            let partition = working[i].as_ref().unwrap().partition.clone();
+                            let msg = "The worker ended without answering.".to_string();
+                            (i, MsgFromWorker::Panicked(partition, msg))
+                        }
+                        (None, Err(_)) => continue,
+                    }
                 }
-                Ok(resp) => {
-                    errors.push(ThreadError::UnexpectedResponse("Unexpected response from thread \
-                                                                 while finding candidat primes"
-                                                                    .to_string(),
-                                                                resp))
+                Err(RecvTimeoutError::Disconnected) => {
+                    errors.push(ThreadError::RecvError(RecvError));
+                    break;
+                }
+            };
+
+            if let MsgFromWorker::Progress(position, unit_found) = resp {
+                if let Some(ref mut unit) = working[i] {
+                    unit.position = position;
+                    unit.found = unit_found;
+                    let partition = unit.partition.clone();
+                    let running = working.iter().flatten();
+                    progress.update(&Progress {
+                        partition,
+                        position,
+                        processed: processed +
+                                   running.clone()
+                            .map(|unit| unit.position.saturating_sub(unit.partition.from))
+                            .sum::<u64>(),
+                        total,
+                        found: found + running.map(|unit| unit.found).sum::<u64>(),
+                    });
                 }
-                Err(err) => errors.push(ThreadError::RecvError(err)),
+                continue;
             }
+
+            let unit = match working[i].take() {
+                Some(unit) => unit,
+                None => {
+                    let msg = format!("Answer from an idle thread while {}", request);
+                    errors.push(ThreadError::UnexpectedResponse(msg, resp));
+                    continue;
+                }
+            };
+
+            match resp {
+                MsgFromWorker::Error(MathError::Cancelled) => cancelled = true,
+                MsgFromWorker::Error(err) => errors.push(ThreadError::Math(err)),
+                MsgFromWorker::Panicked(partition, msg) => {
+                    // A thread that ended without answering may have left a message.
+                    let msg = self.respawn(i).unwrap_or(msg);
+                    if unit.retries < MAX_RETRIES {
+                        let retry = Unit {
+                            retries: unit.retries + 1,
+                            position: unit.partition.from,
+                            found: 0,
+                            ..unit
+                        };
+                        let sent = self.threads.borrow()[i].send(retry.instruction.clone());
+                        match sent {
+                            Ok(_) => {
+                                working[i] = Some(retry);
+                                continue;
+                            }
+                            Err(err) => errors.push(ThreadError::SendError(err)),
+                        }
+                    } else {
+                        errors.push(ThreadError::Panicked(Some(partition), msg));
+                    }
+                }
+                resp => {
+                    match unpack(resp) {
+                        Ok(result) => {
+                            completed(unit.id, &result);
+                            results[unit.id] = Some(result);
+                            processed += unit.partition.delta;
+                            found += unit.found;
+                        }
+                        Err(resp) => {
+                            let msg = format!("Unexpected response from thread while {}",
+                                              request);
+                            errors.push(ThreadError::UnexpectedResponse(msg, resp))
+                        }
+                    }
+                }
+            }
+            working[i] = self.hand_out(i, &mut queue, &mut results, &mut errors);
+        }
+        if !in_stages {
+            progress.finish();
+            // A deadline set for this request must not cancel the next one.
+            self.cancel.reset();
         }
 
-        if errors.len() > 0 {
-            Err(errors)
+        // Units that were never handed out were held back by the cancel.
+        if (cancelled || results.len() < no_units) && errors.is_empty() {
+            return Err(ThreadPoolError::Cancelled);
+        }
+        if errors.is_empty() {
+            Ok(results.into_iter().flatten().collect())
         } else {
-            Ok(found_candidates)
+            Err(ThreadPoolError::Thread(errors))
         }
-
     }
 
+    /// Sends the next unit of the queue to the i-th thread, and returns it unless the
+    /// queue is empty or the request cancelled. A thread that cannot be reached is
+    /// replaced, and its unit counts as failed.
+    fn hand_out<I, T>(&self,
+                      i: usize,
+                      queue: &mut I,
+                      results: &mut Vec<Option<T>>,
+                      errors: &mut Vec<ThreadError>)
+                      -> Option<Unit>
+        where I: Iterator<Item = (usize, MsgToWorker)>
+    {
+        if self.cancel.is_cancelled() {
+            return None;
+        }
+        for (id, instruction) in queue {
+            results.push(None);
+            let sent = self.threads.borrow()[i].send(instruction.clone());
+            match sent {
+                Ok(_) => {
+                    let partition = instruction.partition();
+                    return Some(Unit {
+                        id,
+                        instruction,
+                        position: partition.from,
+                        partition,
+                        retries: 0,
+                        found: 0,
+                    });
+                }
+                Err(err) => {
+                    errors.push(ThreadError::SendError(err));
+                    self.respawn(i);
+                }
+            }
+        }
+        None
+    }
 
-    pub fn sieve(&self,
-                 prime_page: Vec<u64>,
-                 candidates: Vec<u64>)
-                 -> Result<Vec<u64>, ThreadPoolError> {
-        let partitions = math::best_partitioning(0, candidates.len(), self.threads.len());
-
-        let threadparts = self.threads.iter().zip(partitions);
-        for (part, thread) in threadparts {}
-
-        unimplemented!();
+    /// Stops and joins every worker. Fails with what went wrong with any of them:
+    /// a worker that is gone already, that does not acknowledge the stop, or that
+    /// panicked.
+    pub fn stop(mut self) -> Result<(), ThreadPoolError> {
+        self.shutdown()
     }
 
-    pub fn stop(&self) -> Result<(), ThreadPoolError> {
+    /// Asks all workers to stop first, so they wind down together, then waits for
+    /// each. Leaves the pool without threads.
+    fn shutdown(&mut self) -> Result<(), ThreadPoolError> {
         let mut errors = Vec::new();
-        for thread in &self.threads {
-
+        let threads: Vec<Thread> = self.threads.get_mut().drain(..).collect();
+        let mut stopping = Vec::with_capacity(threads.len());
+        for thread in &threads {
             match thread.send(MsgToWorker::Stop) {
-                Err(err) => errors.push(ThreadError::SendError(err)),
-                Ok(_) => continue,
+                Ok(_) => stopping.push(true),
+                Err(err) => {
+                    errors.push(ThreadError::SendError(err));
+                    stopping.push(false);
+                }
             }
+        }
 
-            match thread.recv() {
-                Err(err) => errors.push(ThreadError::RecvError(err)),
-                Ok(_) => {}
+        // Joining first means every acknowledgement is in the channel by now, and a
+        // worker that died after taking the stop cannot keep us waiting.
+        for thread in threads {
+            if let Err(msg) = thread.join() {
+                errors.push(ThreadError::Panicked(None, msg));
+            }
+        }
+        while let Ok((i, resp)) = self.answers.try_recv() {
+            match resp {
+                MsgFromWorker::Ok => stopping[i] = false,
+                resp => {
+                    let msg = "Unexpected response from thread while stopping".to_string();
+                    errors.push(ThreadError::UnexpectedResponse(msg, resp))
+                }
             }
         }
+        for _ in stopping.iter().filter(|&&unanswered| unanswered) {
+            errors.push(ThreadError::RecvError(RecvError));
+        }
 
-        if errors.len() == 0 {
-            Err(ThreadPoolError::Thread(errors))
-        } else {
+        if errors.is_empty() {
             Ok(())
+        } else {
+            Err(ThreadPoolError::Thread(errors))
         }
     }
 }
 
+impl Drop for ThreadPool {
+    /// Stops the workers unless `stop` did already, so none outlives the pool.
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
 pub enum ThreadError {
     RecvError(RecvError),
     SendError(SendError<MsgToWorker>),
     UnexpectedResponse(String, MsgFromWorker),
+    Math(MathError),
+    /// A thread panicked, on the partition if it was working on one.
+    Panicked(Option<Partition>, String),
 }
 
 pub enum ThreadPoolError {
     Math(MathError),
     Thread(Vec<ThreadError>),
+    Config(ConfigError),
+    /// Pinning was asked for, but workers could not be pinned to these CPUs. None
+    /// if pinning is not supported at all.
+    Unpinned(Vec<usize>),
+    /// The request was cancelled through the pool's `CancelToken`.
+    Cancelled,
 }
 
 impl From<MathError> for ThreadPoolError {
@@ -142,34 +548,508 @@ impl From<MathError> for ThreadPoolError {
     }
 }
 
+impl From<ConfigError> for ThreadPoolError {
+    fn from(err: ConfigError) -> Self {
+        ThreadPoolError::Config(err)
+    }
+}
+
 impl Display for ThreadPoolError {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        match self {
-            &ThreadPoolError::Thread(ref errors) => {
+        match *self {
+            ThreadPoolError::Thread(ref errors) => {
                 let _ = write!(f, "One or more errors occured in the thread pool \n\t");
                 for err in errors {
-                    let _ = match err {
-                        &ThreadError::RecvError(err) => {
+                    let _ = match *err {
+                        ThreadError::RecvError(err) => {
                             write!(f, "Failed to read from a thread. Err: {}", err)
                         }
-                        &ThreadError::SendError(ref err) => {
+                        ThreadError::SendError(ref err) => {
                             write!(f, "Failed to send to a thread. Err: {}", err)
                         }
-                        &ThreadError::UnexpectedResponse(ref req, ref resp) => {
+                        ThreadError::UnexpectedResponse(ref req, ref resp) => {
                             write!(f,
                                    "Unexpected response! Thread answered '{}' on request '{}' ",
                                    req,
                                    resp)
                         }
+                        ThreadError::Math(ref err) => {
+                            write!(f, "A thread failed to compute its share. Err: {:?}", err)
+                        }
+                        ThreadError::Panicked(Some(ref partition), ref msg) => {
+                            write!(f, "A thread panicked on {:?}. Err: {}", partition, msg)
+                        }
+                        ThreadError::Panicked(None, ref msg) => {
+                            write!(f, "A thread panicked. Err: {}", msg)
+                        }
                     };
                 }
                 write!(f, "\n\t")
             }
 
-            &ThreadPoolError::Math(MathError::Limit(ref msg)) => {
+            ThreadPoolError::Config(ref err) => write!(f, "{}", err),
+
+            ThreadPoolError::Unpinned(ref cpus) if cpus.is_empty() => {
+                write!(f, "Workers cannot be pinned to CPUs on this system.")
+            }
+
+            ThreadPoolError::Unpinned(ref cpus) => {
+                write!(f, "Workers could not be pinned to the CPUs {:?}.", cpus)
+            }
+
+            ThreadPoolError::Math(MathError::Limit(ref msg)) => {
                 write!(f, "Math limit reached: {}", msg)
             }
+
+            ThreadPoolError::Math(MathError::Unsorted(ref msg)) => {
+                write!(f, "Numbers out of order: {}", msg)
+            }
+
+            ThreadPoolError::Math(MathError::Cancelled) |
+            ThreadPoolError::Cancelled => write!(f, "The request was cancelled."),
         }
 
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ThreadError, ThreadPool, ThreadPoolError, MAX_RETRIES, UNITS_PER_THREAD};
+    use std::result::Result;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Duration;
+    use sieve::math::{CandidateSet, Partition, Wheel};
+    use sieve::worker::{MsgToWorker, MsgFromWorker};
+    use sieve::thread::Send;
+    use sieve::progress::{Progress, ProgressSink};
+    use sieve::cancel::CancelToken;
+    use test_util::is_prime;
+    use config::Config;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn pool(threads: usize, max_ppt: usize) -> ThreadPool {
+        ThreadPool::new(threads, max_ppt)
+    }
+
+    fn unwrap<T>(result: Result<T, ThreadPoolError>) -> T {
+        match result {
+            Ok(value) => value,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    fn primes_in(from: u64, to: u64) -> Vec<u64> {
+        (from..to).filter(|&n| is_prime(n)).collect()
+    }
+
+    /// Splits the numbers into sets of at most `size` numbers each.
+    fn sets(numbers: &[u64], size: usize) -> Vec<CandidateSet> {
+        let wheel = Arc::new(Wheel::new(3));
+        numbers.chunks(size)
+            .map(|chunk| CandidateSet::from_numbers(wheel.clone(), chunk).unwrap())
+            .collect()
+    }
+
+    fn numbers(sets: &[CandidateSet]) -> Vec<u64> {
+        sets.iter().flat_map(CandidateSet::iter).collect()
+    }
+
+    #[test]
+    fn sieve_over_pages_matches_single_page() {
+        let pool = pool(3, 1000);
+        let candidates: Vec<u64> = (32..1000).collect();
+
+        let single = unwrap(pool.sieve(vec![2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31],
+                                       sets(&candidates, 300)));
+
+        let mut paged = sets(&candidates, 300);
+        for page in [vec![2, 3, 5, 7], vec![11, 13], vec![17, 19, 23, 29, 31]] {
+            paged = unwrap(pool.sieve(page, paged));
+        }
+
+        assert_eq!(numbers(&single), numbers(&paged));
+        assert_eq!(numbers(&single), primes_in(32, 1000));
+    }
+
+    #[test]
+    fn sieve_keeps_order_with_more_sets_than_threads() {
+        let pool = pool(2, 1000);
+        let candidates: Vec<u64> = (100..200).collect();
+        let sieved = unwrap(pool.sieve(vec![2, 3, 5, 7, 11, 13], sets(&candidates, 7)));
+
+        assert_eq!(sieved.len(), 15);
+        assert_eq!(numbers(&sieved), primes_in(100, 200));
+    }
+
+    #[test]
+    fn sieve_of_no_candidates_is_empty() {
+        let pool = pool(2, 1000);
+        let sieved = unwrap(pool.sieve(vec![2, 3, 5], vec![]));
+        assert!(sieved.is_empty());
+    }
+
+    #[test]
+    fn find_candidates_then_sieve_yields_primes() {
+        let pool = pool(2, 1000);
+        let candidates = unwrap(pool.find_candidates(vec![2, 3, 5, 7]));
+        let primes = unwrap(pool.sieve(vec![11, 13], candidates));
+
+        assert_eq!(numbers(&primes), primes_in(8, 49));
+    }
+
+    #[test]
+    fn find_candidates_grows_past_the_window() {
+        let pool = pool(3, 100);
+        let candidates = unwrap(pool.find_candidates(primes_in(2, 212)));
+
+        assert_eq!(numbers(&candidates), primes_in(212, 312));
+    }
+
+    #[test]
+    fn units_come_back_in_order() {
+        let pool = pool(3, 10_000);
+        let init_primes = primes_in(2, 102);
+        let candidates = unwrap(pool.find_candidates(init_primes));
+
+        assert_eq!(candidates.len(), 3 * UNITS_PER_THREAD);
+        assert!(candidates.windows(2).all(|pair| pair[0].to() == pair[1].from()));
+        assert_eq!(numbers(&candidates), primes_in(102, 10_102));
+    }
+
+    #[test]
+    fn new_makes_do_with_any_numbers() {
+        for &(threads, max_ppt) in &[(0, 1000), (2, usize::MAX)] {
+            let pool = pool(threads, max_ppt);
+            let candidates = unwrap(pool.find_candidates(vec![2, 3, 5, 7]));
+            assert_eq!(numbers(&candidates), primes_in(8, 49));
+        }
+    }
+
+    #[test]
+    fn from_config_refuses_invalid_configs() {
+        let config = Config {
+            cores: 0,
+            ..Config::default()
+        };
+        match ThreadPool::from_config(&config) {
+            Err(ThreadPoolError::Config(_)) => {}
+            _ => panic!("a pool without cores was started"),
+        }
+    }
+
+    #[test]
+    fn every_unit_is_handed_over_once_it_is_done() {
+        let pool = pool(3, 10_000);
+        let units = unwrap(pool.round_units(101, 102));
+        let mut done = vec![None; units.len()];
+        let candidates = unwrap(pool.find_candidates_in(primes_in(2, 102), units, |i, set| {
+            assert!(done[i].is_none());
+            done[i] = Some(set.iter().collect::<Vec<u64>>());
+        }));
+
+        let done: Vec<u64> = done.into_iter().flat_map(Option::unwrap).collect();
+        assert_eq!(done, numbers(&candidates));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn pinned_workers_sieve_like_free_ones() {
+        let pool = unwrap(ThreadPool::from_config(&Config {
+            cores: 3,
+            max_mem_usage: 3000,
+            pin_cores: true,
+            ..Config::default()
+        }));
+        let candidates = unwrap(pool.find_candidates(vec![2, 3, 5, 7, 11]));
+        let found: Vec<u64> = candidates.iter().flat_map(CandidateSet::iter).collect();
+        assert_eq!(found, primes_in(12, 121));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn unpinnable_cpus_are_reported() {
+        let config = Config {
+            cores: 2,
+            max_mem_usage: 2000,
+            ..Config::default()
+        };
+        let (pool, unpinned) = ThreadPool::start(&config, vec![1 << 20]);
+        assert_eq!(unpinned, vec![1 << 20, 1 << 20]);
+        let candidates = unwrap(pool.find_candidates(vec![2, 3, 5, 7]));
+        let found: Vec<u64> = candidates.iter().flat_map(CandidateSet::iter).collect();
+        assert_eq!(found, primes_in(8, 49));
+    }
+
+    #[test]
+    fn dispatch_surfaces_worker_errors() {
+        let pool = pool(2, 1000);
+        let primes = Arc::new(vec![2, 3]);
+        let instructions = vec![MsgToWorker::FindCandidates(primes.clone(),
+                                                            Partition {
+                                                                from: 3,
+                                                                delta: 10,
+                                                            }),
+                                MsgToWorker::FindCandidates(primes,
+                                                            Partition {
+                                                                from: 13,
+                                                                delta: 1000,
+                                                            })];
+        let unpack = |resp| match resp {
+            MsgFromWorker::CandidatesResult(candidates) => Ok(candidates),
+            resp => Err(resp),
+        };
+
+        match pool.dispatch(instructions.into_iter(), "testing", unpack, |_, _| {}) {
+            Err(ThreadPoolError::Thread(errors)) => assert_eq!(errors.len(), 1),
+            _ => panic!("a worker sieved without enough initial primes"),
+        }
+
+        // The pool must still answer correctly after a failed request.
+        let sieved = unwrap(pool.sieve(vec![2, 3], sets(&[5, 9], 2)));
+        assert_eq!(numbers(&sieved), vec![5]);
+    }
+
+    #[test]
+    fn stop_succeeds_for_healthy_workers() {
+        let pool = pool(3, 1000);
+        unwrap(pool.find_candidates(vec![2, 3, 5, 7]));
+        assert!(pool.stop().is_ok());
+    }
+
+    #[test]
+    fn stop_reports_a_worker_that_is_gone() {
+        let pool = pool(3, 1000);
+        assert!(pool.threads.borrow()[1].send(MsgToWorker::Stop).is_ok());
+        match pool.answers.recv() {
+            Ok((1, MsgFromWorker::Ok)) => {}
+            _ => panic!("the worker did not acknowledge the stop"),
+        }
+
+        match pool.stop() {
+            Err(ThreadPoolError::Thread(errors)) => assert_eq!(errors.len(), 1),
+            _ => panic!("stopping a stopped worker went unnoticed"),
+        }
+    }
+
+    fn panicking(pool: &ThreadPool, times: usize) -> Result<Vec<()>, ThreadPoolError> {
+        let countdown = Arc::new(AtomicUsize::new(times));
+        let instructions = vec![MsgToWorker::Panic(countdown)];
+        pool.dispatch(instructions.into_iter(),
+                      "testing",
+                      |resp| match resp {
+                          MsgFromWorker::Ok => Ok(()),
+                          resp => Err(resp),
+                      },
+                      |_, _| {})
+    }
+
+    #[test]
+    fn dead_worker_is_replaced_and_retried() {
+        let pool = pool(2, 1000);
+        match panicking(&pool, MAX_RETRIES) {
+            Ok(results) => assert_eq!(results.len(), 1),
+            Err(_) => panic!("the instruction was not retried"),
+        }
+
+        let sieved = unwrap(pool.sieve(vec![2, 3], sets(&[5, 9, 11, 15], 2)));
+        assert_eq!(numbers(&sieved), vec![5, 11]);
+        assert!(pool.stop().is_ok());
+    }
+
+    #[test]
+    fn workers_that_vanish_are_replaced() {
+        let pool = pool(2, 1000);
+        let instructions = vec![MsgToWorker::Vanish, MsgToWorker::Vanish];
+        let unpack = |resp| match resp {
+            MsgFromWorker::Ok => Ok(()),
+            resp => Err(resp),
+        };
+        match pool.dispatch(instructions.into_iter(), "testing", unpack, |_, _| {}) {
+            Err(ThreadPoolError::Thread(errors)) => {
+                assert_eq!(errors.len(), 2);
+                match errors[0] {
+                    ThreadError::Panicked(Some(_), ref msg) => {
+                        assert_eq!(msg, "The worker ended without answering.")
+                    }
+                    _ => panic!("the worker was not reported"),
+                }
+            }
+            _ => panic!("a vanished worker answered"),
+        }
+
+        let sieved = unwrap(pool.sieve(vec![2, 3], sets(&[5, 9, 11, 15], 2)));
+        assert_eq!(numbers(&sieved), vec![5, 11]);
+        assert!(pool.stop().is_ok());
+    }
+
+    #[test]
+    fn retries_are_bounded() {
+        let pool = pool(2, 1000);
+        match panicking(&pool, MAX_RETRIES + 1) {
+            Err(ThreadPoolError::Thread(errors)) => {
+                assert_eq!(errors.len(), 1);
+                match errors[0] {
+                    ThreadError::Panicked(Some(_), ref msg) => assert_eq!(msg, "told to panic"),
+                    _ => panic!("the panic was not reported"),
+                }
+            }
+            _ => panic!("a panicking instruction succeeded"),
+        }
+
+        // The replacement is ready for the next request.
+        let sieved = unwrap(pool.sieve(vec![2, 3], sets(&[5, 9, 11, 15], 2)));
+        assert_eq!(numbers(&sieved), vec![5, 11]);
+        assert!(pool.stop().is_ok());
+    }
+
+    /// What a sink was told: (request or stage or "update" or "finish", processed,
+    /// total, found).
+    type Report = (String, u64, u64, u64);
+
+    struct Recorder(Rc<RefCell<Vec<Report>>>);
+
+    impl ProgressSink for Recorder {
+        fn start(&mut self, request: &str, total: u64) {
+            self.0.borrow_mut().push((request.to_string(), 0, total, 0));
+        }
+
+        fn stage(&mut self, stage: &str, total: u64) {
+            self.0.borrow_mut().push((stage.to_string(), 0, total, 0));
+        }
+
+        fn update(&mut self, progress: &Progress) {
+            assert!(progress.partition.from <= progress.position);
+            assert!(progress.position <= progress.partition.from + progress.partition.delta);
+            self.0
+                .borrow_mut()
+                .push(("update".to_string(), progress.processed, progress.total, progress.found));
+        }
+
+        fn finish(&mut self) {
+            self.0.borrow_mut().push(("finish".to_string(), 0, 0, 0));
+        }
+    }
+
+    #[test]
+    fn progress_reaches_the_sink() {
+        let mut pool = pool(3, 10_000);
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        pool.set_progress_sink(Box::new(Recorder(reports.clone())));
+        unwrap(pool.find_candidates(primes_in(2, 102)));
+
+        let reports = reports.borrow();
+        assert_eq!(reports[0], ("finding candidate primes".to_string(), 0, 10_000, 0));
+        assert_eq!(reports[reports.len() - 2],
+                   ("update".to_string(), 10_000, 10_000, primes_in(102, 10_102).len() as u64));
+        assert_eq!(reports[reports.len() - 1].0, "finish");
+        let processed: Vec<u64> = reports.iter().filter(|r| r.0 == "update").map(|r| r.1).collect();
+        assert!(processed.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn combined_requests_report_in_stages() {
+        let mut pool = pool(2, 1000);
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        pool.set_progress_sink(Box::new(Recorder(reports.clone())));
+        pool.as_one_request("both", || {
+            unwrap(pool.find_candidates(vec![2, 3, 5, 7]));
+            unwrap(pool.sieve(vec![2, 3], sets(&[5, 9, 11, 15], 2)))
+        });
+
+        let reports = reports.borrow();
+        let calls: Vec<&str> =
+            reports.iter().filter(|r| r.0 != "update").map(|r| r.0.as_str()).collect();
+        assert_eq!(calls, vec!["both", "finding candidate primes", "sieving candidates", "finish"]);
+        // Each stage counts up to its own total, and never back.
+        let mut stage = (0, 0); // processed and total
+        for report in reports.iter() {
+            if report.0 == "update" {
+                assert_eq!(report.2, stage.1);
+                assert!(stage.0 <= report.1 && report.1 <= report.2);
+                stage.0 = report.1;
+            } else {
+                assert_eq!(stage.0, stage.1, "a stage ended short of its total");
+                stage = (0, report.2);
+            }
+        }
+        assert_eq!(reports[1].2, 41);
+        assert_eq!(reports[reports.len() - 2].2, 10);
+
+    }
+
+    fn is_cancelled<T>(result: Result<T, ThreadPoolError>) -> bool {
+        matches!(result, Err(ThreadPoolError::Cancelled))
+    }
+
+    #[test]
+    fn cancelled_requests_leave_the_pool_usable() {
+        let pool = pool(2, 10_000);
+        let token = pool.cancel_token();
+        thread::spawn(move || token.cancel()).join().unwrap();
+        assert!(is_cancelled(pool.find_candidates(primes_in(2, 102))));
+
+        // The cancel is used up, and no stale answer is in the way.
+        let candidates = unwrap(pool.find_candidates(primes_in(2, 102)));
+        assert_eq!(numbers(&candidates), primes_in(102, 10_102));
+
+        pool.cancel_token().cancel_after(Duration::from_millis(0));
+        assert!(is_cancelled(pool.sieve(vec![2, 3], sets(&[5, 9, 11, 15], 2))));
+        let sieved = unwrap(pool.sieve(vec![2, 3], sets(&[5, 9, 11, 15], 2)));
+        assert_eq!(numbers(&sieved), vec![5, 11]);
+        assert!(pool.stop().is_ok());
+    }
+
+    #[test]
+    fn deadlines_end_with_their_request() {
+        let pool = pool(2, 1000);
+        pool.cancel_token().cancel_after(Duration::from_millis(100));
+        unwrap(pool.sieve(vec![2, 3], sets(&[5, 9, 11, 15], 2)));
+        thread::sleep(Duration::from_millis(150));
+        let sieved = unwrap(pool.sieve(vec![2, 3], sets(&[5, 9, 11, 15], 2)));
+        assert_eq!(numbers(&sieved), vec![5, 11]);
+
+        // Inside `as_one_request` the deadline holds for every stage.
+        let stages = pool.as_one_request("both", || {
+            pool.cancel_token().cancel_after(Duration::from_millis(100));
+            unwrap(pool.sieve(vec![2, 3], sets(&[5, 9, 11, 15], 2)));
+            thread::sleep(Duration::from_millis(150));
+            pool.sieve(vec![2, 3], sets(&[5, 9, 11, 15], 2))
+        });
+        assert!(is_cancelled(stages));
+        assert!(!pool.cancel_token().is_cancelled());
+        assert!(pool.stop().is_ok());
+    }
+
+    /// Cancels at the first report of a worker, which comes in while its unit runs
+    /// or right before its result.
+    struct Canceller(CancelToken);
+
+    impl ProgressSink for Canceller {
+        fn start(&mut self, _: &str, _: u64) {}
+
+        fn stage(&mut self, _: &str, _: u64) {}
+
+        fn update(&mut self, _: &Progress) {
+            self.0.cancel();
+        }
+
+        fn finish(&mut self) {}
+    }
+
+    #[test]
+    fn cancel_stops_running_units() {
+        let mut pool = pool(2, 50_000_000);
+        let token = pool.cancel_token();
+        pool.set_progress_sink(Box::new(Canceller(token)));
+        let units = unwrap(pool.round_units(9_973, 10_000));
+        let no_units = units.len();
+        let mut completed = 0;
+        let found = pool.find_candidates_in(primes_in(2, 10_000), units, |_, _| completed += 1);
+        assert!(is_cancelled(found));
+        assert!(completed < no_units);
+        assert!(pool.stop().is_ok());
+    }
+}
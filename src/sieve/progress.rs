@@ -0,0 +1,45 @@
+use sieve::math::Partition;
+
+/// Where a request of the pool stands. Sent to the progress sink whenever a worker
+/// reports on its work unit.
+pub struct Progress {
+    /// The work unit of the reporting worker, and how far into it it got.
+    pub partition: Partition,
+    pub position: u64,
+    /// Numbers sieved so far, over all units of the request or its stage.
+    pub processed: u64,
+    /// Numbers in all units of the request or its stage.
+    pub total: u64,
+    /// Candidates or primes in the units finished so far.
+    pub found: u64,
+}
+
+/// Receives the progress of the thread pool's requests. Calls come from the thread
+/// that made the request, in the order start, updates, finish, with stages in
+/// between for a request made of several.
+pub trait ProgressSink {
+    /// A request over `total` numbers starts, `request` says what it does.
+    fn start(&mut self, request: &str, total: u64);
+
+    /// A stage of the request starts, over `total` numbers of its own. The updates
+    /// after it count within the stage.
+    fn stage(&mut self, stage: &str, total: u64);
+
+    fn update(&mut self, progress: &Progress);
+
+    /// The request is over, successfully or not.
+    fn finish(&mut self);
+}
+
+/// The sink of a pool nobody watches.
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn start(&mut self, _: &str, _: u64) {}
+
+    fn stage(&mut self, _: &str, _: u64) {}
+
+    fn update(&mut self, _: &Progress) {}
+
+    fn finish(&mut self) {}
+}
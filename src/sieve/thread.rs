@@ -1,25 +1,52 @@
-use std::sync::mpsc::{Sender, Receiver, SendError, RecvError};
+use std::any::Any;
+use std::sync::mpsc::{Sender, SendError};
+use std::thread::JoinHandle;
 use sieve::worker::{MsgToWorker, MsgFromWorker};
 use std::result::Result;
 
-pub type Thread = (Sender<MsgToWorker>, Receiver<MsgFromWorker>);
+/// What a worker answers, with the place of the worker in the pool. All workers of
+/// a pool answer through the same channel.
+pub type Answer = (usize, MsgFromWorker);
 
-pub trait Send {
-    fn send(&self, MsgToWorker) -> Result<(), SendError<MsgToWorker>>;
+/// A worker thread and the channel to give it instructions.
+pub struct Thread {
+    sender: Sender<MsgToWorker>,
+    handle: JoinHandle<()>,
 }
 
-pub trait Receive {
-    fn recv(&self) -> Result<MsgFromWorker, RecvError>;
+impl Thread {
+    pub fn new(sender: Sender<MsgToWorker>, handle: JoinHandle<()>) -> Thread {
+        Thread { sender, handle }
+    }
+
+    /// Whether the thread has ended, so that it will not answer any more.
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Waits for the thread to end. Fails with the panic message if it panicked.
+    pub fn join(self) -> Result<(), String> {
+        self.handle.join().map_err(|payload| panic_message(&*payload))
+    }
 }
 
-impl Send for Thread {
-    fn send(&self, msg: MsgToWorker) -> Result<(), SendError<MsgToWorker>> {
-        self.0.send(msg)
+/// The message a panic was started with, as far as it is a string.
+pub fn panic_message(payload: &(dyn Any + ::std::marker::Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "Box<dyn Any>".to_string()
     }
 }
 
-impl Receive for Thread {
-    fn recv(&self) -> Result<MsgFromWorker, RecvError> {
-        self.1.recv()
+pub trait Send {
+    fn send(&self, msg: MsgToWorker) -> Result<(), SendError<MsgToWorker>>;
+}
+
+impl Send for Thread {
+    fn send(&self, msg: MsgToWorker) -> Result<(), SendError<MsgToWorker>> {
+        self.sender.send(msg)
     }
 }
@@ -1,89 +1,202 @@
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::thread;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::fmt::{Display, Result as FmtResult, Formatter};
-use sieve::math::{find_candidates, sieve_page, MathError, Partition};
+use sieve::affinity::pin_current_thread;
+use sieve::cancel::CancelToken;
+use sieve::thread::{panic_message, Answer, Thread};
+use sieve::math::{find_candidates_with, sieve_page_with, CandidateSet, MathError, Partition, Wheel};
 
 pub type ArcVec = Arc<Vec<u64>>;
 
+/// Least time between two progress reports of a worker.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Clone)]
 pub enum MsgToWorker {
     FindCandidates(ArcVec, Partition),
-    Sieve(ArcVec, ArcVec),
+    /// The pool keeps the candidates to retry with, so the worker sieves a copy of
+    /// them unless it holds the only handle.
+    Sieve(ArcVec, Arc<CandidateSet>),
     Stop,
+    /// Panics as long as the counter is above zero, counting it down, and answers
+    /// `Ok` after that.
+    #[cfg(test)]
+    Panic(Arc<AtomicUsize>),
+    /// Ends the worker without an answer, as if it was killed.
+    #[cfg(test)]
+    Vanish,
+}
+
+impl MsgToWorker {
+    /// The range of numbers the instruction works on.
+    pub fn partition(&self) -> Partition {
+        match *self {
+            MsgToWorker::FindCandidates(_, ref partition) => partition.clone(),
+            MsgToWorker::Sieve(_, ref set) => {
+                Partition {
+                    from: set.from(),
+                    delta: set.to() - set.from(),
+                }
+            }
+            _ => Partition { from: 0, delta: 0 },
+        }
+    }
 }
 
 pub enum MsgFromWorker {
-    CandidatesResult(Vec<u64>),
-    SieveResult(Vec<u64>),
+    CandidatesResult(CandidateSet),
+    SieveResult(CandidateSet),
     Error(MathError),
+    /// The work on the partition panicked with the message. The worker ends after
+    /// sending this.
+    Panicked(Partition, String),
+    /// The number the worker got to in its partition, and the candidates or primes
+    /// it found there. Sent now and then while working, and once more just before
+    /// the result.
+    Progress(u64, u64),
+    /// Acknowledges `Stop`, the last message of a worker.
     Ok,
 }
 
-pub fn new_worker() -> (Sender<MsgToWorker>, Receiver<MsgFromWorker>) {
+/// Starts the worker for the given place of the pool, pinned to the CPU if one is
+/// given, and tells whether that worked. It answers through `send`, tagged with its
+/// place, and gives up on its instruction once the token is cancelled.
+pub fn new_worker(place: usize,
+                  wheel: Arc<Wheel>,
+                  cpu: Option<usize>,
+                  send: Sender<Answer>,
+                  cancel: CancelToken)
+                  -> (Thread, bool) {
     let (s_tw, r_tw) = channel();
-    let (s_fw, r_fw) = channel();
+    let (pinned_to, pinned) = channel();
 
-    thread::spawn(move || { worker(s_fw, r_tw); });
+    let handle = thread::spawn(move || {
+        let _ = pinned_to.send(cpu.is_none_or(pin_current_thread));
+        worker(place, &wheel, send, r_tw, &cancel);
+    });
 
-    (s_tw, r_fw)
+    let pinned = pinned.recv().unwrap_or(false);
+    (Thread::new(s_tw, handle), pinned)
 }
 
-fn worker(send: Sender<MsgFromWorker>, rec: Receiver<MsgToWorker>) {
-    loop {
-        let msg = match rec.recv() {
-            Ok(msg) => msg,
-            Err(_) => break,
-        };
-
+fn worker(place: usize,
+          wheel: &Arc<Wheel>,
+          send: Sender<Answer>,
+          rec: Receiver<MsgToWorker>,
+          cancel: &CancelToken) {
+    while let Ok(msg) = rec.recv() {
+        let partition = msg.partition();
         let ans = match msg {
-
-            MsgToWorker::FindCandidates(init_primes, partition) => {
-                match find_candidates(&init_primes, partition) {
-                    Ok(candidates) => MsgFromWorker::CandidatesResult(candidates),
-                    Err(err) => MsgFromWorker::Error(err),
-                }
+            MsgToWorker::Stop => {
+                let _ = send.send((place, MsgFromWorker::Ok));
+                break;
             }
 
-            MsgToWorker::Sieve(primes_page, candidates) => {
-                match sieve_page(&primes_page, &candidates) {
-                    Ok(primes) => MsgFromWorker::SieveResult(primes),
-                    Err(err) => MsgFromWorker::Error(err),
+            #[cfg(test)]
+            MsgToWorker::Vanish => break,
+
+            // Whatever the panic left half done is dropped with this thread, the pool
+            // starts a fresh one.
+            msg => {
+                let mut last_report = Instant::now();
+                let report = |position, found| {
+                    if last_report.elapsed() >= PROGRESS_INTERVAL {
+                        let _ = send.send((place, MsgFromWorker::Progress(position, found)));
+                        last_report = Instant::now();
+                    }
+                    !cancel.is_cancelled()
+                };
+                match catch_unwind(AssertUnwindSafe(|| work(wheel, msg, report))) {
+                    Ok(ans) => ans,
+                    Err(payload) => {
+                        let msg = panic_message(&*payload);
+                        let _ = send.send((place, MsgFromWorker::Panicked(partition, msg)));
+                        break;
+                    }
                 }
             }
-
-            MsgToWorker::Stop => break,
         };
 
-        match send.send(ans) {
-            Ok(_) => (),
-            Err(_) => panic!(),
+        match ans {
+            MsgFromWorker::CandidatesResult(ref set) |
+            MsgFromWorker::SieveResult(ref set) => {
+                let _ = send.send((place, MsgFromWorker::Progress(set.to(), set.len() as u64)));
+            }
+            _ => {}
+        }
+        // Nobody is left to answer to once the pool is gone.
+        if send.send((place, ans)).is_err() {
+            break;
         }
-
     }
-
-    cleanup()
 }
 
-fn cleanup() {}
+fn work<F>(wheel: &Arc<Wheel>, msg: MsgToWorker, report: F) -> MsgFromWorker
+    where F: FnMut(u64, u64) -> bool
+{
+    match msg {
+        MsgToWorker::FindCandidates(init_primes, partition) => {
+            match find_candidates_with(wheel, &init_primes, partition, report) {
+                Ok(candidates) => MsgFromWorker::CandidatesResult(candidates),
+                Err(err) => MsgFromWorker::Error(err),
+            }
+        }
+
+        MsgToWorker::Sieve(primes_page, candidates) => {
+            let candidates = Arc::try_unwrap(candidates).unwrap_or_else(|set| (*set).clone());
+            match sieve_page_with(&primes_page, candidates, report) {
+                Ok(primes) => MsgFromWorker::SieveResult(primes),
+                Err(err) => MsgFromWorker::Error(err),
+            }
+        }
+
+        #[cfg(test)]
+        MsgToWorker::Panic(countdown) => {
+            if countdown.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok() {
+                panic!("told to panic");
+            }
+            MsgFromWorker::Ok
+        }
 
+        MsgToWorker::Stop => MsgFromWorker::Ok,
+        #[cfg(test)]
+        MsgToWorker::Vanish => MsgFromWorker::Ok,
+    }
+}
 
 impl Display for MsgToWorker {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        match self {
-            &MsgToWorker::FindCandidates(_, _) => write!(f, "FindCandidates(...)"),
-            &MsgToWorker::Sieve(_, _) => write!(f, "Sieve(...)"),
-            &MsgToWorker::Stop => write!(f, "Stop"),
+        match *self {
+            MsgToWorker::FindCandidates(_, _) => write!(f, "FindCandidates(...)"),
+            MsgToWorker::Sieve(_, _) => write!(f, "Sieve(...)"),
+            MsgToWorker::Stop => write!(f, "Stop"),
+            #[cfg(test)]
+            MsgToWorker::Panic(_) => write!(f, "Panic(...)"),
+            #[cfg(test)]
+            MsgToWorker::Vanish => write!(f, "Vanish"),
         }
     }
 }
 
 impl Display for MsgFromWorker {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        match self {
-            &MsgFromWorker::CandidatesResult(_) => write!(f, "CandidatesResult(...)"),
-            &MsgFromWorker::SieveResult(_) => write!(f, "SieveResult(...)"),
-            &MsgFromWorker::Error(_) => write!(f, "Error(...)"),
-            &MsgFromWorker::Ok => write!(f, "Ok"),
+        match *self {
+            MsgFromWorker::CandidatesResult(_) => write!(f, "CandidatesResult(...)"),
+            MsgFromWorker::SieveResult(_) => write!(f, "SieveResult(...)"),
+            MsgFromWorker::Error(_) => write!(f, "Error(...)"),
+            MsgFromWorker::Panicked(ref partition, ref msg) => {
+                write!(f, "Panicked({:?}, {})", partition, msg)
+            }
+            MsgFromWorker::Progress(position, found) => {
+                write!(f, "Progress({}, {})", position, found)
+            }
+            MsgFromWorker::Ok => write!(f, "Ok"),
         }
     }
 }
@@ -0,0 +1,28 @@
+use std::env;
+use std::fs::remove_file;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Decides primality by trial division, as an oracle for the sieves.
+pub fn is_prime(n: u64) -> bool {
+    n >= 2 && (2..n).take_while(|d| d * d <= n).all(|d| !n.is_multiple_of(d))
+}
+
+/// Path of a scratch file of its own for the test, cleaned up if a previous run left
+/// it behind.
+pub fn temp_file(name: &str) -> String {
+    let no = TEMP_FILES.fetch_add(1, Ordering::SeqCst);
+    let path = env::temp_dir().join(format!("prime_sieve_{}_{}_{}", process::id(), no, name));
+    let path = path.to_str().unwrap().to_string();
+    clean_up(&path);
+    path
+}
+
+/// Removes the primes file and the files kept next to it.
+pub fn clean_up(fname: &str) {
+    let _ = remove_file(fname);
+    let _ = remove_file(format!("{}.idx", fname));
+    let _ = remove_file(format!("{}.journal", fname));
+}
@@ -0,0 +1,380 @@
+extern crate prime_sieve;
+
+use prime_sieve::config::Config;
+use prime_sieve::engine;
+use prime_sieve::fs::{self, Encoding, FileError, PrimesReader};
+use prime_sieve::sieve::{math, CancelToken, Progress, ProgressSink, ThreadPool, ThreadPoolError};
+use prime_sieve::sieve::math::{CandidateSet, Partition, Wheel};
+use std::cell::Cell;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+
+#[path = "../src/test_util.rs"]
+mod test_util;
+use test_util::{clean_up, is_prime, temp_file};
+
+fn config(file: String, cores: usize) -> Config {
+    Config {
+        file,
+        cores,
+        max_mem_usage: cores << 16,
+        ..Config::default()
+    }
+}
+
+fn thread_pool(config: &Config) -> ThreadPool {
+    match ThreadPool::from_config(config) {
+        Ok(thread_pool) => thread_pool,
+        Err(err) => panic!("{}", err),
+    }
+}
+
+fn read_all(fname: &str) -> Vec<u64> {
+    let mut reader = match fs::read_primes(fname.to_string()) {
+        Ok(reader) => reader,
+        Err(err) => panic!("{}", err),
+    };
+    let mut primes = Vec::new();
+    for chunk in reader.chunks() {
+        match chunk {
+            Ok(chunk) => primes.extend_from_slice(&chunk),
+            Err(err) => panic!("{}", err),
+        }
+    }
+    primes
+}
+
+#[test]
+fn sieve_rounds_grow_the_primes_file() {
+    let fname = temp_file("rounds");
+    let config = config(fname.clone(), 3);
+    let thread_pool = thread_pool(&config);
+
+    for _ in 0..3 {
+        let primes = match engine::sieve(&thread_pool, &config) {
+            Ok(primes) => primes,
+            Err(err) => panic!("{}", err),
+        };
+        assert!(engine::save(&primes, &config).is_ok());
+    }
+
+    let primes = read_all(&fname);
+    let highest = *primes.last().unwrap();
+    assert_eq!(highest, 12_763);
+    assert_eq!(primes, (0..highest + 1).filter(|&n| is_prime(n)).collect::<Vec<u64>>());
+    assert!(thread_pool.stop().is_ok());
+    clean_up(&fname);
+}
+
+#[test]
+fn sieve_rounds_continue_from_the_highest_prime_over_many_pages() {
+    let fname = temp_file("pages");
+    let config = Config {
+        file: fname.clone(),
+        cores: 2,
+        max_mem_usage: 1 << 12,
+        ..Config::default()
+    };
+    let thread_pool = thread_pool(&config);
+
+    let mut highest = 0;
+    for _ in 0..12 {
+        let primes = match engine::sieve_round(&thread_pool, &config) {
+            Ok(primes) => primes,
+            Err(err) => panic!("{}", err),
+        };
+        assert!(primes[0] > highest);
+        highest = *primes.last().unwrap();
+    }
+
+    assert!(highest > 15_000);
+    assert_eq!(read_all(&fname), (0..highest + 1).filter(|&n| is_prime(n)).collect::<Vec<u64>>());
+    assert!(thread_pool.stop().is_ok());
+    clean_up(&fname);
+}
+
+/// Cancels the pool at the first report of a unit that is done, and keeps the total
+/// of the requests after that.
+struct Watcher {
+    cancel: Option<CancelToken>,
+    total: Rc<Cell<u64>>,
+}
+
+impl ProgressSink for Watcher {
+    fn start(&mut self, _: &str, _: u64) {}
+
+    fn stage(&mut self, _: &str, _: u64) {}
+
+    fn update(&mut self, progress: &Progress) {
+        if self.cancel.is_none() {
+            self.total.set(progress.total);
+        } else if progress.position == progress.partition.from + progress.partition.delta {
+            self.cancel.take().unwrap().cancel();
+        }
+    }
+
+    fn finish(&mut self) {}
+}
+
+#[test]
+fn interrupted_rounds_resume_from_their_checkpoints() {
+    let fname = temp_file("resume");
+    let config = Config {
+        file: fname.clone(),
+        cores: 2,
+        max_mem_usage: 1 << 12,
+        ..Config::default()
+    };
+    let mut thread_pool = thread_pool(&config);
+    for _ in 0..6 {
+        assert!(engine::sieve_round(&thread_pool, &config).is_ok());
+    }
+    assert!(!Path::new(&format!("{}.journal", fname)).exists());
+
+    // A round without saving keeps no journal.
+    let round = match engine::sieve_file(&thread_pool, &config) {
+        Ok(primes) => primes,
+        Err(err) => panic!("{}", err),
+    };
+    let pager = fs::load_primes(fname.clone()).ok().unwrap();
+    assert!(round[0] > pager.header().highest_prime);
+    assert!(!Path::new(&format!("{}.journal", fname)).exists());
+
+    // A round that is cancelled once its first unit is done leaves that unit
+    // checkpointed, but not all of them.
+    let total = Rc::new(Cell::new(0));
+    let watcher = Watcher {
+        cancel: Some(thread_pool.cancel_token()),
+        total: total.clone(),
+    };
+    thread_pool.set_progress_sink(Box::new(watcher));
+    match engine::sieve_round(&thread_pool, &config) {
+        Err(engine::SieveError::Thread(ThreadPoolError::Cancelled)) => {}
+        _ => panic!("the round was not cancelled"),
+    }
+    let round_key = fs::Round {
+        highest_prime: pager.header().highest_prime,
+        count: pager.header().count,
+        page_size: config.page_size() as u64,
+    };
+    let mut journal = fs::open_journal(&fname, &round_key, vec![]).ok().unwrap();
+    let units = journal.units().to_vec();
+    let checkpoints = journal.take_checkpoints();
+    assert!(!checkpoints.is_empty());
+    assert!(checkpoints.len() < units.len());
+    drop(journal);
+
+    // The next run only sieves the units without a checkpoint, and saving the round
+    // drops its journal.
+    let round_size = units[units.len() - 1].1 - units[0].0;
+    let resumed = match engine::sieve_round(&thread_pool, &config) {
+        Ok(primes) => primes,
+        Err(err) => panic!("{}", err),
+    };
+    assert_eq!(resumed, round);
+    assert!(0 < total.get() && total.get() < round_size);
+    assert!(!Path::new(&format!("{}.journal", fname)).exists());
+    let primes = read_all(&fname);
+    let highest = *primes.last().unwrap();
+    assert_eq!(primes, (0..highest + 1).filter(|&n| is_prime(n)).collect::<Vec<u64>>());
+    assert!(thread_pool.stop().is_ok());
+    clean_up(&fname);
+}
+
+#[test]
+fn sieve_starts_from_scratch_without_a_file() {
+    let config = config(temp_file("missing"), 1);
+    let thread_pool = thread_pool(&config);
+    match engine::sieve(&thread_pool, &config) {
+        Ok(primes) => assert_eq!(primes, math::init_primes()),
+        Err(err) => panic!("{}", err),
+    }
+
+    match fs::load_primes(temp_file("missing")) {
+        Err(FileError::IO(ref err)) if err.kind() == ErrorKind::NotFound => {}
+        _ => panic!("missing file was loaded"),
+    }
+}
+
+#[test]
+fn thread_pool_agrees_with_the_math_helpers() {
+    let thread_pool = thread_pool(&config(temp_file("unused"), 2));
+    let candidates = match thread_pool.find_candidates(math::init_primes()) {
+        Ok(candidates) => candidates,
+        Err(err) => panic!("{}", err),
+    };
+    let from_pool: Vec<u64> = candidates.iter().flat_map(CandidateSet::iter).collect();
+
+    let part = Partition {
+        from: 12,
+        delta: 109,
+    };
+    let from_math = match math::find_candidates(&Arc::new(Wheel::new(3)), &math::init_primes(), part) {
+        Ok(candidates) => candidates.iter().collect::<Vec<u64>>(),
+        Err(err) => panic!("{:?}", err),
+    };
+
+    assert_eq!(from_pool, from_math);
+    assert_eq!(from_pool, (12..121).filter(|&n| is_prime(n)).collect::<Vec<u64>>());
+}
+
+#[test]
+fn stored_primes_are_reachable_through_the_index() {
+    let fname = temp_file("index");
+    let primes: Vec<u64> = (0..300_000).filter(|&n| is_prime(n)).collect();
+    assert!(fs::save_primes(&primes[..1_000], fname.clone()).is_ok());
+    assert!(fs::save_primes(&primes, fname.clone()).is_ok());
+
+    let mut index = match fs::open_index(fname.clone()) {
+        Ok(index) => index,
+        Err(err) => panic!("{}", err),
+    };
+    assert_eq!(index.header().count, primes.len() as u64);
+    assert_eq!(index.nth(1).ok().unwrap(), Some(2));
+    assert_eq!(index.nth(10_000).ok().unwrap(), Some(104_729));
+    assert_eq!(index.count_up_to(100_000).ok().unwrap(), Some(9_592));
+    assert_eq!(index.primes_between(99_980, 100_050).ok().unwrap(),
+               vec![99_989, 99_991, 100_003, 100_019, 100_043, 100_049]);
+
+    match fs::read_primes(fname.clone()) {
+        Ok(PrimesReader::Mapped(mapped)) => assert_eq!(mapped.block(0)[..3], [2, 3, 5]),
+        Ok(PrimesReader::Buffered(_)) => {}
+        Err(err) => panic!("{}", err),
+    }
+    assert_eq!(read_all(&fname), primes);
+    clean_up(&fname);
+}
+
+#[test]
+fn prime_count_agrees_with_the_primes_file() {
+    let fname = temp_file("count");
+    let primes: Vec<u64> = (0..200_000).filter(|&n| is_prime(n)).collect();
+    assert!(fs::save_primes(&primes, fname.clone()).is_ok());
+    let config = Config {
+        max_mem_usage: 1 << 21,
+        ..config(fname.clone(), 1)
+    };
+
+    let mut index = match fs::open_index(fname.clone()) {
+        Ok(index) => index,
+        Err(err) => panic!("{}", err),
+    };
+    for &x in &[0, 2, 1_000, 65_535, 104_729, 199_999] {
+        let in_file = index.count_up_to(x).ok().unwrap().unwrap();
+        assert_eq!(math::prime_count(x, &primes), in_file);
+        assert_eq!(math::prime_count(x, &[]), in_file);
+    }
+
+    match engine::count_primes(&config, 10_000_000_000) {
+        Ok(count) => assert_eq!(count, 455_052_511),
+        Err(err) => panic!("{}", err),
+    }
+    match engine::count_primes(&config, u64::MAX) {
+        Err(engine::SieveError::Limit(_)) => {}
+        _ => panic!("counted up to 2^64 in 2 MiB"),
+    }
+    clean_up(&fname);
+}
+
+#[test]
+fn nth_prime_with_and_without_the_primes_file() {
+    let fname = temp_file("nth");
+    let config = config(fname.clone(), 2);
+    let thread_pool = thread_pool(&config);
+    let nth = |n| match engine::nth_prime(&thread_pool, &config, n) {
+        Ok(prime) => prime,
+        Err(err) => panic!("{}", err),
+    };
+
+    let expected = [(0, None), (1, Some(2)), (5, Some(11)), (6, Some(13)), (26, Some(101)),
+                    (1_000, Some(7_919)), (78_498, Some(999_983)), (1_000_000, Some(15_485_863))];
+    for &(n, prime) in &expected {
+        assert_eq!(nth(n), prime, "prime number {}", n);
+    }
+    for &n in &[1_000_000_000_000, engine::PRIMES_IN_U64 + 1, u64::MAX] {
+        match engine::nth_prime(&thread_pool, &config, n) {
+            Err(engine::SieveError::Limit(_)) => {}
+            _ => panic!("found prime number {} in 128 KiB", n),
+        }
+    }
+
+    let primes: Vec<u64> = (0..200_000).filter(|&n| is_prime(n)).collect();
+    assert!(fs::save_primes(&primes, fname.clone()).is_ok());
+    assert_eq!(nth(17_984), Some(199_999));
+    for &(n, prime) in &expected {
+        assert_eq!(nth(n), prime, "prime number {}", n);
+    }
+    assert!(thread_pool.stop().is_ok());
+    clean_up(&fname);
+}
+
+#[test]
+fn is_prime_agrees_with_the_primes_file() {
+    let fname = temp_file("is_prime");
+    let config = config(fname.clone(), 1);
+    let primes: Vec<u64> = (0..100_000).filter(|&n| is_prime(n)).collect();
+    assert!(fs::save_primes(&primes, fname.clone()).is_ok());
+
+    let mut stored = primes.iter().peekable();
+    for n in 0..100_000 {
+        let in_file = stored.peek() == Some(&&n);
+        if in_file {
+            stored.next();
+        }
+        assert_eq!(math::is_prime(n), in_file, "{}", n);
+        if n % 997 == 0 || n > 99_900 {
+            assert_eq!(engine::is_prime(&config, n).ok(), Some(in_file), "{}", n);
+        }
+    }
+    assert_eq!(engine::is_prime(&config, 18_446_744_073_709_551_557).ok(), Some(true));
+    clean_up(&fname);
+}
+
+#[test]
+fn is_prime_u128_uses_the_primes_file_for_trial_division() {
+    let fname = temp_file("is_prime_u128");
+    // Pages of 256 primes, so the primes below 2^16 take many of them.
+    let config = Config {
+        max_mem_usage: 1 << 12,
+        ..config(fname.clone(), 1)
+    };
+    let primes: Vec<u64> = (0..70_000).filter(|&n| is_prime(n)).collect();
+
+    let p = 18_446_744_073_709_551_557u128;
+    let q = 65_521u128; // largest prime below 2^16
+    for encoding in [None, Some(Encoding::Raw), Some(Encoding::DeltaVarint)] {
+        if let Some(encoding) = encoding {
+            assert!(fs::save_primes_as(&primes, fname.clone(), encoding).is_ok());
+        }
+        assert_eq!(engine::is_prime_u128(&config, (1 << 127) - 1).ok(), Some(true));
+        assert_eq!(engine::is_prime_u128(&config, u128::MAX - 158).ok(), Some(true));
+        assert_eq!(engine::is_prime_u128(&config, p * q).ok(), Some(false));
+        assert_eq!(engine::is_prime_u128(&config, p * p).ok(), Some(false));
+        assert_eq!(engine::is_prime_u128(&config, 65_537).ok(), Some(true));
+    }
+    clean_up(&fname);
+}
+
+#[test]
+fn factor_streams_the_primes_file() {
+    let fname = temp_file("factor");
+    let config = config(fname.clone(), 1);
+    let primes: Vec<u64> = (0..200_000).filter(|&n| is_prime(n)).collect();
+
+    let cases: [(u64, Vec<(u64, u32)>); 4] =
+        [(1, vec![]),
+         (2 * 2 * 199_999 * 199_999, vec![(2, 2), (199_999, 2)]),
+         (999_983 * 1_000_003 * 13, vec![(13, 1), (999_983, 1), (1_000_003, 1)]),
+         (4_294_967_291 * 4_294_967_279, vec![(4_294_967_279, 1), (4_294_967_291, 1)])];
+    for &file in &[false, true] {
+        if file {
+            assert!(fs::save_primes(&primes, fname.clone()).is_ok());
+        }
+        for &(n, ref expected) in cases.iter() {
+            assert_eq!(engine::factor(&config, n).ok().as_ref(), Some(expected), "{}", n);
+        }
+    }
+    clean_up(&fname);
+}
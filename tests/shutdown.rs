@@ -0,0 +1,65 @@
+// Counts the threads of the whole process, so it lives in its own test binary where
+// no other test starts threads at the same time.
+
+#![cfg(target_os = "linux")]
+
+extern crate prime_sieve;
+
+use prime_sieve::config::Config;
+use prime_sieve::sieve::ThreadPool;
+use std::fs::read_dir;
+use std::thread::sleep;
+use std::time::Duration;
+
+fn threads() -> usize {
+    read_dir("/proc/self/task").map(|tasks| tasks.count()).unwrap_or(0)
+}
+
+/// Waits a little for the count to settle: a joined thread can still be listed for
+/// a moment while the kernel tears it down.
+fn threads_settle_at(expected: usize) -> bool {
+    for _ in 0..100 {
+        if threads() == expected {
+            return true;
+        }
+        sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+fn config(cores: usize) -> Config {
+    Config {
+        cores,
+        max_mem_usage: cores << 16,
+        ..Config::default()
+    }
+}
+
+fn start_pool(config: &Config) -> ThreadPool {
+    match ThreadPool::from_config(config) {
+        Ok(thread_pool) => thread_pool,
+        Err(err) => panic!("{}", err),
+    }
+}
+
+#[test]
+fn no_worker_outlives_its_pool() {
+    let before = threads();
+
+    let thread_pool = start_pool(&config(4));
+    assert!(threads_settle_at(before + 4));
+    assert!(thread_pool.find_candidates(vec![2, 3, 5, 7]).is_ok());
+    drop(thread_pool);
+    assert!(threads_settle_at(before));
+
+    let thread_pool = start_pool(&config(3));
+    assert!(thread_pool.stop().is_ok());
+    assert!(threads_settle_at(before));
+
+    // A failed request leaves the workers running, and dropping still ends them.
+    for _ in 0..5 {
+        let thread_pool = start_pool(&config(2));
+        assert!(thread_pool.find_candidates_from(vec![2, 3], 1 << 40).is_err());
+    }
+    assert!(threads_settle_at(before));
+}